@@ -0,0 +1,305 @@
+//! Typed model of `llvm-cov export -format=text`.
+//!
+//! llvm-cov writes segments, regions and branches as positional arrays whose
+//! length changes between LLVM versions. They are read into named fields here
+//! and written back as objects by `--json`.
+//!
+//! The schema is documented for users in `docs/json.md`; keep it in sync, and
+//! bump `SCHEMA_VERSION` when a field is removed or changes meaning.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version of the schema written by `--json`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Output of `llvm-cov export -format=text`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Export {
+    pub data: Vec<ExportData>,
+}
+
+/// One entry per `llvm-cov export` invocation; there is only ever one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ExportData {
+    pub files: Vec<File>,
+    pub functions: Vec<Function>,
+    pub totals: Summary,
+}
+
+/// Coverage of a source file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct File {
+    pub filename: String,
+    /// Where the execution count changes, in source order.
+    pub segments: Vec<Segment>,
+    #[serde(default)]
+    pub branches: Vec<Branch>,
+    pub summary: Summary,
+}
+
+impl File {
+    /// Execution count of each line with code, the same way `llvm-cov show`
+    /// counts them.
+    pub fn line_counts(&self) -> BTreeMap<u64, u64> {
+        fn is_start_of_region(segment: &Segment) -> bool {
+            !segment.is_gap_region && segment.has_count && segment.is_region_entry
+        }
+
+        let mut counts = BTreeMap::new();
+        let (first, last) = match (self.segments.first(), self.segments.last()) {
+            (Some(first), Some(last)) => (first.line, last.line),
+            _ => return counts,
+        };
+        let mut wrapped: Option<&Segment> = None;
+        let mut segments = self.segments.iter().peekable();
+        for line in first..=last {
+            let mut line_segments = vec![];
+            while let Some(segment) = segments.next_if(|segment| segment.line == line) {
+                line_segments.push(segment);
+            }
+
+            let regions = line_segments
+                .iter()
+                .filter(|segment| is_start_of_region(segment))
+                .count();
+            let start_of_skipped = matches!(
+                line_segments.first(),
+                Some(segment) if !segment.has_count && segment.is_region_entry
+            );
+            let mapped = !start_of_skipped
+                && (matches!(wrapped, Some(segment) if segment.has_count) || regions > 0);
+            if mapped {
+                let count = line_segments
+                    .iter()
+                    .filter(|segment| is_start_of_region(segment))
+                    .map(|segment| segment.count)
+                    .chain(wrapped.map(|segment| segment.count))
+                    .max()
+                    .unwrap_or(0);
+                counts.insert(line, count);
+            }
+
+            if let Some(segment) = line_segments.last() {
+                wrapped = Some(segment);
+            }
+        }
+        counts
+    }
+
+    /// Branch conditions by line, as `(covered, total)`; each branch has a
+    /// true and a false condition.
+    pub fn branch_conditions(&self) -> BTreeMap<u64, (u64, u64)> {
+        let mut conditions = BTreeMap::new();
+        for branch in &self.branches {
+            let (covered, total) = conditions.entry(branch.line_start).or_insert((0, 0));
+            *covered += (branch.true_count > 0) as u64 + (branch.false_count > 0) as u64;
+            *total += 2;
+        }
+        conditions
+    }
+}
+
+/// Coverage of a function, with all of its instantiations merged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Function {
+    /// Demangled name.
+    pub name: String,
+    /// How many times the function was entered.
+    pub count: u64,
+    /// Source files, indexed by `file_id` of the regions and branches.
+    pub filenames: Vec<String>,
+    pub regions: Vec<Region>,
+    #[serde(default)]
+    pub branches: Vec<Branch>,
+}
+
+/// Execution count from `line`:`column` up to the next segment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Value>")]
+pub struct Segment {
+    pub line: u64,
+    pub column: u64,
+    pub count: u64,
+    /// False for code without coverage mapping, e.g. comments.
+    pub has_count: bool,
+    pub is_region_entry: bool,
+    /// Whitespace or braces between regions. Not reported before LLVM 11.
+    pub is_gap_region: bool,
+}
+
+/// A counted source range of a function.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Value>")]
+pub struct Region {
+    pub line_start: u64,
+    pub column_start: u64,
+    pub line_end: u64,
+    pub column_end: u64,
+    pub execution_count: u64,
+    pub file_id: u64,
+    pub expanded_file_id: u64,
+    /// 0 code, 1 expansion, 2 skipped, 3 gap, 4 branch.
+    pub kind: u64,
+}
+
+/// A condition and how often it was true and false.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Value>")]
+pub struct Branch {
+    pub line_start: u64,
+    pub column_start: u64,
+    pub line_end: u64,
+    pub column_end: u64,
+    pub true_count: u64,
+    pub false_count: u64,
+    pub file_id: u64,
+    pub expanded_file_id: u64,
+    pub kind: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub lines: Count,
+    pub functions: Count,
+    pub instantiations: Count,
+    pub regions: Count,
+    /// Absent before LLVM 12.
+    #[serde(default)]
+    pub branches: Count,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Count {
+    pub count: u64,
+    pub covered: u64,
+    /// Percentage of `covered` in `count`, 0 when `count` is 0.
+    pub percent: f64,
+}
+
+/// What `--json` writes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Coverage {
+    pub version: u32,
+    pub title: String,
+    pub files: Vec<File>,
+    pub functions: Vec<Function>,
+    pub totals: Summary,
+}
+
+impl Coverage {
+    pub fn new(export: Export, title: &str) -> anyhow::Result<Self> {
+        let data = export
+            .data
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no data in llvm-cov export."))?;
+        Ok(Self {
+            version: SCHEMA_VERSION,
+            title: title.to_owned(),
+            files: data.files,
+            functions: data.functions,
+            totals: data.totals,
+        })
+    }
+}
+
+/// The first `N` values, padding fields added by newer LLVM versions with `None`.
+fn fields<'a, const N: usize>(
+    name: &str,
+    values: &'a [Value],
+    min: usize,
+) -> Result<[Option<&'a Value>; N], String> {
+    if values.len() < min {
+        return Err(format!(
+            "{} needs at least {} fields, got {}.",
+            name,
+            min,
+            values.len()
+        ));
+    }
+    let mut fields = [None; N];
+    for (field, value) in fields.iter_mut().zip(values) {
+        *field = Some(value);
+    }
+    Ok(fields)
+}
+
+fn number(value: Option<&Value>) -> Result<u64, String> {
+    match value {
+        None => Ok(0),
+        Some(value) => value
+            .as_u64()
+            .ok_or_else(|| format!("expected a number, got {}.", value)),
+    }
+}
+
+/// llvm-cov writes flags as numbers in some versions and as booleans in others.
+fn flag(value: Option<&Value>) -> Result<bool, String> {
+    match value {
+        None => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(value) => value
+            .as_u64()
+            .map(|n| n != 0)
+            .ok_or_else(|| format!("expected a flag, got {}.", value)),
+    }
+}
+
+impl TryFrom<Vec<Value>> for Segment {
+    type Error = String;
+
+    fn try_from(values: Vec<Value>) -> Result<Self, Self::Error> {
+        let [line, column, count, has_count, is_region_entry, is_gap_region] =
+            fields::<6>("segment", &values, 5)?;
+        Ok(Self {
+            line: number(line)?,
+            column: number(column)?,
+            count: number(count)?,
+            has_count: flag(has_count)?,
+            is_region_entry: flag(is_region_entry)?,
+            is_gap_region: flag(is_gap_region)?,
+        })
+    }
+}
+
+impl TryFrom<Vec<Value>> for Region {
+    type Error = String;
+
+    fn try_from(values: Vec<Value>) -> Result<Self, Self::Error> {
+        let [line_start, column_start, line_end, column_end, execution_count, file_id, expanded_file_id, kind] =
+            fields::<8>("region", &values, 8)?;
+        Ok(Self {
+            line_start: number(line_start)?,
+            column_start: number(column_start)?,
+            line_end: number(line_end)?,
+            column_end: number(column_end)?,
+            execution_count: number(execution_count)?,
+            file_id: number(file_id)?,
+            expanded_file_id: number(expanded_file_id)?,
+            kind: number(kind)?,
+        })
+    }
+}
+
+impl TryFrom<Vec<Value>> for Branch {
+    type Error = String;
+
+    fn try_from(values: Vec<Value>) -> Result<Self, Self::Error> {
+        let [line_start, column_start, line_end, column_end, true_count, false_count, file_id, expanded_file_id, kind] =
+            fields::<9>("branch", &values, 9)?;
+        Ok(Self {
+            line_start: number(line_start)?,
+            column_start: number(column_start)?,
+            line_end: number(line_end)?,
+            column_end: number(column_end)?,
+            true_count: number(true_count)?,
+            false_count: number(false_count)?,
+            file_id: number(file_id)?,
+            expanded_file_id: number(expanded_file_id)?,
+            kind: number(kind)?,
+        })
+    }
+}
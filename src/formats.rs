@@ -0,0 +1,302 @@
+//! Report formats of other tools, generated from `export::Coverage`.
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use anyhow::Context;
+use md5::{Digest, Md5};
+use serde::Serialize;
+
+use crate::export::{Coverage, File};
+
+/// A crate and the directory of its manifest, for grouping files.
+#[derive(Debug)]
+pub struct Crate<'a> {
+    pub name: &'a str,
+    pub dir: &'a Path,
+}
+
+/// The crate whose directory is the closest ancestor of `filename`.
+fn crate_of<'a>(crates: &'a [Crate<'a>], filename: &str) -> Option<&'a Crate<'a>> {
+    crates
+        .iter()
+        .filter(|c| Path::new(filename).starts_with(c.dir))
+        .max_by_key(|c| c.dir.components().count())
+}
+
+/// `filename` relative to `root`, with `/` separators. Files outside of
+/// `root` keep their absolute path.
+pub fn relative(root: &Path, filename: &str) -> String {
+    match Path::new(filename).strip_prefix(root) {
+        Ok(path) => path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => filename.to_owned(),
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Covered and valid lines and branch conditions.
+#[derive(Debug, Default, Clone, Copy)]
+struct Rates {
+    lines_covered: u64,
+    lines_valid: u64,
+    branches_covered: u64,
+    branches_valid: u64,
+}
+
+impl Rates {
+    fn of(file: &File) -> Self {
+        let lines = file.line_counts();
+        let (branches_covered, branches_valid) = file
+            .branch_conditions()
+            .values()
+            .fold((0, 0), |(c, v), (covered, total)| (c + covered, v + total));
+        Self {
+            lines_covered: lines.values().filter(|count| **count > 0).count() as u64,
+            lines_valid: lines.len() as u64,
+            branches_covered,
+            branches_valid,
+        }
+    }
+
+    fn add(&mut self, other: Self) {
+        self.lines_covered += other.lines_covered;
+        self.lines_valid += other.lines_valid;
+        self.branches_covered += other.branches_covered;
+        self.branches_valid += other.branches_valid;
+    }
+
+    fn line_rate(&self) -> f64 {
+        rate(self.lines_covered, self.lines_valid)
+    }
+
+    fn branch_rate(&self) -> f64 {
+        rate(self.branches_covered, self.branches_valid)
+    }
+}
+
+fn rate(covered: u64, valid: u64) -> f64 {
+    if valid == 0 {
+        0.0
+    } else {
+        covered as f64 / valid as f64
+    }
+}
+
+/// Cobertura XML with a package per crate and a class per file. `timestamp`
+/// is in milliseconds since the epoch, as coverage.py writes it.
+pub fn cobertura(coverage: &Coverage, root: &Path, crates: &[Crate], timestamp: u128) -> String {
+    let mut packages = Vec::<(&str, Rates, String)>::new();
+    let mut total = Rates::default();
+    for file in &coverage.files {
+        let name = crate_of(crates, &file.filename).map_or("", |c| c.name);
+        let filename = xml_escape(&relative(root, &file.filename));
+        let rates = Rates::of(file);
+        total.add(rates);
+
+        let branches = file.branch_conditions();
+        let mut class = format!(
+            "        <class name=\"{}\" filename=\"{}\" line-rate=\"{}\" branch-rate=\"{}\" complexity=\"0\">\n          <methods/>\n          <lines>\n",
+            filename,
+            filename,
+            rates.line_rate(),
+            rates.branch_rate()
+        );
+        for (line, count) in file.line_counts() {
+            match branches.get(&line) {
+                Some((covered, total)) => class.push_str(&format!(
+                    "            <line number=\"{}\" hits=\"{}\" branch=\"true\" condition-coverage=\"{}% ({}/{})\"/>\n",
+                    line,
+                    count,
+                    covered * 100 / total,
+                    covered,
+                    total
+                )),
+                None => class.push_str(&format!(
+                    "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>\n",
+                    line, count
+                )),
+            }
+        }
+        class.push_str("          </lines>\n        </class>\n");
+
+        match packages.iter_mut().find(|(n, _, _)| *n == name) {
+            Some((_, package_rates, classes)) => {
+                package_rates.add(rates);
+                classes.push_str(&class);
+            }
+            None => packages.push((name, rates, class)),
+        }
+    }
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" ?>\n");
+    xml.push_str(
+        "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+    );
+    xml.push_str(&format!(
+        "<coverage line-rate=\"{}\" branch-rate=\"{}\" lines-covered=\"{}\" lines-valid=\"{}\" branches-covered=\"{}\" branches-valid=\"{}\" complexity=\"0\" version=\"{}\" timestamp=\"{}\">\n",
+        total.line_rate(),
+        total.branch_rate(),
+        total.lines_covered,
+        total.lines_valid,
+        total.branches_covered,
+        total.branches_valid,
+        env!("CARGO_PKG_VERSION"),
+        timestamp
+    ));
+    xml.push_str(&format!(
+        "  <sources>\n    <source>{}</source>\n  </sources>\n  <packages>\n",
+        xml_escape(&root.to_string_lossy())
+    ));
+    for (name, rates, classes) in packages {
+        xml.push_str(&format!(
+            "    <package name=\"{}\" line-rate=\"{}\" branch-rate=\"{}\" complexity=\"0\">\n      <classes>\n{}      </classes>\n    </package>\n",
+            xml_escape(name),
+            rates.line_rate(),
+            rates.branch_rate(),
+            classes
+        ));
+    }
+    xml.push_str("  </packages>\n</coverage>\n");
+    xml
+}
+
+/// SonarQube generic test coverage XML.
+pub fn sonar(coverage: &Coverage, root: &Path) -> String {
+    let mut xml = String::from("<coverage version=\"1\">\n");
+    for file in &coverage.files {
+        xml.push_str(&format!(
+            "  <file path=\"{}\">\n",
+            xml_escape(&relative(root, &file.filename))
+        ));
+        let branches = file.branch_conditions();
+        for (line, count) in file.line_counts() {
+            match branches.get(&line) {
+                Some((covered, total)) => xml.push_str(&format!(
+                    "    <lineToCover lineNumber=\"{}\" covered=\"{}\" branchesToCover=\"{}\" coveredBranches=\"{}\"/>\n",
+                    line,
+                    count > 0,
+                    total,
+                    covered
+                )),
+                None => xml.push_str(&format!(
+                    "    <lineToCover lineNumber=\"{}\" covered=\"{}\"/>\n",
+                    line,
+                    count > 0
+                )),
+            }
+        }
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</coverage>\n");
+    xml
+}
+
+/// Hits of a line in Codecov's JSON format.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Hits {
+    Count(u64),
+    /// `"<covered>/<total>"` branch conditions.
+    Branches(String),
+}
+
+/// Codecov's JSON coverage format, keyed by path and line number.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Codecov {
+    pub coverage: BTreeMap<String, BTreeMap<u64, Hits>>,
+}
+
+pub fn codecov(coverage: &Coverage, root: &Path) -> Codecov {
+    let coverage = coverage
+        .files
+        .iter()
+        .map(|file| {
+            let branches = file.branch_conditions();
+            let lines = file
+                .line_counts()
+                .into_iter()
+                .map(|(line, count)| match branches.get(&line) {
+                    Some((covered, total)) => {
+                        (line, Hits::Branches(format!("{}/{}", covered, total)))
+                    }
+                    None => (line, Hits::Count(count)),
+                })
+                .collect();
+            (relative(root, &file.filename), lines)
+        })
+        .collect();
+    Codecov { coverage }
+}
+
+/// Coveralls' `source_files` format, without the job fields the upload step adds.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Coveralls {
+    pub source_files: Vec<SourceFile>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SourceFile {
+    pub name: String,
+    /// MD5 of the source.
+    pub source_digest: String,
+    /// Hits per line, starting at line 1; `null` for lines without code.
+    pub coverage: Vec<Option<u64>>,
+    /// Flattened `[line, block, branch, hits]` quadruples.
+    pub branches: Vec<u64>,
+}
+
+/// `read` returns the contents of a source file, which Coveralls needs for
+/// the digest and line count.
+pub fn coveralls(
+    coverage: &Coverage,
+    root: &Path,
+    read: impl Fn(&str) -> io::Result<Vec<u8>>,
+) -> anyhow::Result<Coveralls> {
+    let mut source_files = vec![];
+    for file in &coverage.files {
+        let source =
+            read(&file.filename).with_context(|| format!("failed to read {}", file.filename))?;
+        let counts = file.line_counts();
+        // Like `str::lines`, a trailing newline does not start another line.
+        let lines = source.iter().filter(|b| **b == b'\n').count() as u64
+            + (!source.is_empty() && !source.ends_with(b"\n")) as u64;
+        let lines = counts.keys().last().map_or(lines, |last| lines.max(*last));
+        let coverage = (1..=lines).map(|line| counts.get(&line).copied()).collect();
+
+        let mut branches = vec![];
+        let mut blocks = BTreeMap::<u64, u64>::new();
+        for branch in &file.branches {
+            let block = blocks.entry(branch.line_start).or_insert(0);
+            branches.extend(&[branch.line_start, *block, 0, branch.true_count]);
+            branches.extend(&[branch.line_start, *block, 1, branch.false_count]);
+            *block += 1;
+        }
+
+        source_files.push(SourceFile {
+            name: relative(root, &file.filename),
+            source_digest: format!("{:x}", Md5::digest(&source)),
+            coverage,
+            branches,
+        });
+    }
+    Ok(Coveralls { source_files })
+}
@@ -1,24 +1,32 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::ffi::OsStr;
 use std::fs;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::process::{
     self, Child as StdChild, ChildStdout as StdChildStdout, Command as StdCommand,
     Output as StdOutput, Stdio,
 };
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use cargo_binutils::Tool;
-use cargo_metadata::Metadata;
-use clap::Clap;
+use cargo_metadata::{Metadata, Package, Version};
+use clap::{AppSettings, Clap};
 
+mod export;
+mod formats;
 #[cfg(test)]
 mod tests;
 
 #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
 struct BuildTarget {
+    name: String,
+    kind: Vec<String>,
     test: bool,
 }
 
@@ -27,17 +35,39 @@ struct BuildProfile {
     test: bool,
 }
 
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+struct Diagnostic {
+    level: String,
+    rendered: Option<String>,
+}
+
+impl Diagnostic {
+    fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+}
+
+/// A line of `cargo --message-format json` output.
+///
+/// Only the fields used here are declared, and reasons this tool does not know
+/// about are parsed as `Other` so that newer cargo versions keep working.
 #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
 #[serde(tag = "reason")]
 enum BuildMessage {
     #[serde(rename = "compiler-message")]
-    CompilerMessage {},
+    CompilerMessage { message: Diagnostic },
 
     #[serde(rename = "compiler-artifact")]
     CompilerArtifact {
+        package_id: String,
+        features: Vec<String>,
         target: BuildTarget,
         profile: BuildProfile,
+        #[serde(default)]
+        filenames: Vec<PathBuf>,
         executable: Option<PathBuf>,
+        #[serde(default)]
+        fresh: bool,
     },
 
     #[serde(rename = "build-script-executed")]
@@ -45,6 +75,9 @@ enum BuildMessage {
 
     #[serde(rename = "build-finished")]
     BuildFinished { success: bool },
+
+    #[serde(other)]
+    Other,
 }
 
 #[cfg(test)]
@@ -64,7 +97,23 @@ impl Output {
         match self {
             Self::Actual(output) => &output.stdout,
             #[cfg(test)]
-            Self::Mock(output, _) => &output,
+            Self::Mock(output, _) => output,
+        }
+    }
+
+    fn stderr(&self) -> &[u8] {
+        match self {
+            Self::Actual(output) => &output.stderr,
+            #[cfg(test)]
+            Self::Mock(..) => &[],
+        }
+    }
+
+    fn status(&self) -> ExitStatus {
+        match self {
+            Self::Actual(output) => ExitStatus(output.status.success()),
+            #[cfg(test)]
+            Self::Mock(_, r) => ExitStatus(*r),
         }
     }
 }
@@ -201,16 +250,19 @@ impl Command {
 struct Profenv {
     profraw_dir: PathBuf,
     profdata: PathBuf,
+    manifest: PathBuf,
 }
 
 impl Profenv {
     fn new(basedir: &Path) -> io::Result<Self> {
         let profraw_dir = basedir.join(format!("profraw-{}", process::id()));
         let profdata = basedir.join("default.profdata");
+        let manifest = Manifest::path(basedir);
         fs::create_dir(&profraw_dir)?;
         Ok(Self {
             profraw_dir,
             profdata,
+            manifest,
         })
     }
 
@@ -218,11 +270,32 @@ impl Profenv {
         self.profraw_dir.join("%p.profraw")
     }
 
+    /// Where processes set up by `show-env` write their profiles.
+    fn external_profraw_dir(basedir: &Path) -> PathBuf {
+        basedir.join("profraw-env")
+    }
+
+    /// `LLVM_PROFILE_FILE` for processes set up by `show-env`. Nothing
+    /// removes it, so `report` can pick the profiles up later.
+    fn external_profraw(basedir: &Path) -> PathBuf {
+        Self::external_profraw_dir(basedir).join("%p.profraw")
+    }
+
     fn profraw_files(&self) -> anyhow::Result<Vec<PathBuf>> {
         let pattern = self.profraw_dir.join("*.profraw");
         let result = glob::glob(pattern.to_string_lossy().as_ref())?.collect::<Result<_, _>>()?;
         Ok(result)
     }
+
+    /// What any run leaves in `basedir` with `--keep` or after a crash.
+    fn leftovers(basedir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let pattern = basedir.join("profraw-*");
+        let mut result =
+            glob::glob(pattern.to_string_lossy().as_ref())?.collect::<Result<Vec<_>, _>>()?;
+        result.push(basedir.join("default.profdata"));
+        result.push(Manifest::path(basedir));
+        Ok(result)
+    }
 }
 
 impl Drop for Profenv {
@@ -234,307 +307,2432 @@ impl Drop for Profenv {
         if let Err(e) = fs::remove_file(&self.profdata) {
             log::warn!("failed to remove dir {}", e);
         }
+        if let Err(e) = fs::remove_file(&self.manifest) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("failed to remove file {}", e);
+            }
+        }
     }
 }
 
-fn cargo() -> PathBuf {
-    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
-    cargo.into()
+/// What `report` needs besides the profdata, saved next to it.
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    executables: Vec<PathBuf>,
+    title: String,
+    ignore: String,
 }
 
-fn cargo_home() -> String {
-    env::var("CARGO_HOME").unwrap_or_default()
-}
+impl Manifest {
+    fn path(basedir: &Path) -> PathBuf {
+        basedir.join("manifest.json")
+    }
 
-fn rustup_home() -> String {
-    env::var("RUSTUP_HOME").unwrap_or_default()
-}
+    /// Executables under the manifest's directory are saved relative to it,
+    /// so the directory still works after moving it, e.g. to merge CI shards.
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let basedir = path.parent().unwrap_or(path);
+        let manifest = Self {
+            executables: self
+                .executables
+                .iter()
+                .map(|exe| exe.strip_prefix(basedir).unwrap_or(exe).to_owned())
+                .collect(),
+            title: self.title.clone(),
+            ignore: self.ignore.clone(),
+        };
+        fs::write(path, serde_json::to_vec_pretty(&manifest)?)
+            .with_context(|| format!("failed to write {}", path.to_string_lossy()))
+    }
 
-fn target_dir(cargo: &Path) -> anyhow::Result<PathBuf> {
-    let metadata = Command::new(cargo).arg("metadata").output()?;
-    let metadata = serde_json::from_slice::<Metadata>(metadata.stdout())?;
-    Ok(metadata.target_directory)
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let manifest =
+            fs::read(path).with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+        let mut manifest: Self = serde_json::from_slice(&manifest)?;
+        let basedir = env::current_dir()?.join(path.parent().unwrap_or(path));
+        for exe in &mut manifest.executables {
+            *exe = basedir.join(&exe);
+        }
+        Ok(manifest)
+    }
 }
 
-fn build(cargo: &Path, target: &Path, profenv: &Profenv) -> anyhow::Result<Vec<PathBuf>> {
-    let mut build_proc = Command::new(cargo)
-        .arg("build")
-        .arg("--message-format")
-        .arg("json")
-        .arg("--tests")
-        .arg("--target-dir")
-        .arg(target)
-        .env("RUSTC_BOOTSTRAP", "1")
-        .env("RUSTFLAGS", "-Zinstrument-coverage")
-        .env("LLVM_PROFILE_FILE", &profenv.profraw())
-        .stdout(Stdio::piped())
-        .spawn()?;
+/// Cargo configuration files, highest precedence first.
+#[derive(Debug, Default)]
+struct CargoConfig {
+    values: Vec<toml::Value>,
+}
 
-    let mut executables = vec![];
+impl CargoConfig {
+    fn load(cwd: &Path) -> anyhow::Result<Self> {
+        let mut dirs = cwd
+            .ancestors()
+            .map(|d| d.join(".cargo"))
+            .collect::<Vec<_>>();
+        let cargo_home = cargo_home();
+        if !cargo_home.is_empty() {
+            dirs.push(PathBuf::from(cargo_home));
+        }
 
-    let stdout = build_proc.take_stdout();
-    let stdout = BufReader::new(stdout);
-    for line in stdout.lines() {
-        let line = line?;
-        let line = serde_json::from_str::<BuildMessage>(&line)?;
-        if let BuildMessage::CompilerArtifact {
-            executable: Some(exe),
-            profile: BuildProfile { test: true },
-            target: BuildTarget { test: true },
-        } = line
-        {
-            executables.push(exe);
+        let mut values = vec![];
+        for dir in dirs {
+            // cargo prefers `config` when both files exist.
+            let file = ["config", "config.toml"]
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|file| file.is_file());
+            if let Some(file) = file {
+                let text = fs::read_to_string(&file)
+                    .with_context(|| format!("failed to read {}", file.to_string_lossy()))?;
+                let value = text
+                    .parse::<toml::Value>()
+                    .with_context(|| format!("failed to parse {}", file.to_string_lossy()))?;
+                values.push(value);
+            }
         }
+        Ok(Self { values })
     }
 
-    let r = build_proc.wait()?;
-    if !r.success() {
-        anyhow::bail!("failed to run cargo build.");
+    fn get(&self, keys: &[&str]) -> Option<&toml::Value> {
+        self.values
+            .iter()
+            .find_map(|value| keys.iter().try_fold(value, |value, key| value.get(key)))
     }
 
-    if executables.is_empty() {
-        anyhow::bail!("no executable found.")
+    /// `[target.'cfg(...)']` tables by key, each from the file with the highest precedence.
+    fn target_cfgs(&self) -> BTreeMap<&str, &toml::Value> {
+        let mut tables = BTreeMap::new();
+        for value in &self.values {
+            if let Some(toml::Value::Table(targets)) = value.get("target") {
+                for (key, table) in targets {
+                    if key.starts_with("cfg(") {
+                        tables.entry(key.as_str()).or_insert(table);
+                    }
+                }
+            }
+        }
+        tables
+    }
+
+    /// `target.'cfg(...)'.<name>` values whose cfg matches, by key.
+    fn matching_cfgs(
+        &self,
+        cfgs: &[CfgOption],
+        name: &str,
+    ) -> anyhow::Result<Vec<(&str, &toml::Value)>> {
+        let mut values = vec![];
+        for (key, table) in self.target_cfgs() {
+            if let Some(value) = table.get(name) {
+                let cfg = Cfg::parse(key).with_context(|| format!("invalid target.'{}'.", key))?;
+                if cfg.matches(cfgs) {
+                    values.push((key, value));
+                }
+            }
+        }
+        Ok(values)
     }
-    Ok(executables)
 }
 
-fn run_test(prog: &Path, profenv: &Profenv) -> anyhow::Result<()> {
-    let r = Command::new(&prog)
-        .arg("--nocapture")
-        .env("LLVM_PROFILE_FILE", profenv.profraw())
-        .status()?;
-    if !r.success() {
-        anyhow::bail!("failed to run executable.");
+/// Same precedence as cargo: `CARGO_TARGET_<TRIPLE>_RUNNER`,
+/// `target.<triple>.runner`, then the one matching `target.'cfg(...)'.runner`.
+fn target_runner(
+    config: &CargoConfig,
+    triple: &str,
+    cfgs: &[CfgOption],
+) -> anyhow::Result<Vec<String>> {
+    let key = format!(
+        "CARGO_TARGET_{}_RUNNER",
+        triple.to_uppercase().replace(&['-', '.'][..], "_")
+    );
+    if let Ok(runner) = env::var(&key) {
+        return Ok(runner.split_whitespace().map(ToOwned::to_owned).collect());
+    }
+
+    if let Some(runner) = config.get(&["target", triple, "runner"]) {
+        return string_list(runner).with_context(|| format!("invalid target.{}.runner.", triple));
+    }
+    match &config.matching_cfgs(cfgs, "runner")?[..] {
+        [] => Ok(vec![]),
+        [(key, runner)] => {
+            string_list(runner).with_context(|| format!("invalid target.'{}'.runner.", key))
+        }
+        matches => anyhow::bail!(
+            "several target.'cfg(...)'.runner match {}: {}",
+            triple,
+            matches
+                .iter()
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
     }
-    Ok(())
 }
 
-fn merge_profdata(llvm_profdata: &Path, profenv: &Profenv) -> anyhow::Result<()> {
-    let result = Command::new(llvm_profdata)
-        .arg("merge")
-        .arg("-sparse")
-        .args(profenv.profraw_files()?)
-        .arg("-o")
-        .arg(&profenv.profdata)
-        .status()?;
-    if !result.success() {
-        anyhow::bail!("failed to run llvm-profdata.");
+/// Reads a config value that is either a space separated string or an array of strings.
+fn string_list(value: &toml::Value) -> anyhow::Result<Vec<String>> {
+    match value {
+        toml::Value::String(value) => Ok(value.split_whitespace().map(ToOwned::to_owned).collect()),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| anyhow::anyhow!("expected a string, found {}.", value))
+            })
+            .collect(),
+        value => anyhow::bail!("expected a string or an array, found {}.", value),
     }
-    Ok(())
 }
 
-fn to_obj_args<'a>(executables: &'a [PathBuf]) -> Vec<&'a OsStr> {
-    let mut r = vec![];
-    let mut iter = executables.iter();
-    if let Some(exe) = iter.next() {
-        r.push(exe.as_ref());
+/// A `name` or `name="value"` of `rustc --print cfg`.
+type CfgOption = (String, Option<String>);
+
+/// Predicate of a `[target.'cfg(...)']` table.
+#[derive(Debug, PartialEq, Eq)]
+enum Cfg {
+    Option(CfgOption),
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CfgToken<'a> {
+    Ident(&'a str),
+    Str(&'a str),
+    Open,
+    Close,
+    Comma,
+    Equals,
+}
+
+impl Cfg {
+    /// Parses a whole `cfg(...)` key.
+    fn parse(key: &str) -> anyhow::Result<Self> {
+        let tokens = Self::tokenize(key)?;
+        let mut rest = &tokens[..];
+        match rest {
+            [CfgToken::Ident("cfg"), CfgToken::Open, ..] => rest = &rest[2..],
+            _ => anyhow::bail!("expected cfg(...)."),
+        }
+        let cfg = Self::parse_predicate(&mut rest)?;
+        if rest != [CfgToken::Close] {
+            anyhow::bail!("expected `)` at the end.");
+        }
+        Ok(cfg)
     }
-    for exe in iter {
-        r.push("-object".as_ref());
-        r.push(exe.as_ref());
+
+    fn tokenize(input: &str) -> anyhow::Result<Vec<CfgToken<'_>>> {
+        let mut tokens = vec![];
+        let mut rest = input.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let (token, len) = match c {
+                '(' => (CfgToken::Open, 1),
+                ')' => (CfgToken::Close, 1),
+                ',' => (CfgToken::Comma, 1),
+                '=' => (CfgToken::Equals, 1),
+                '"' => {
+                    let end = rest[1..]
+                        .find('"')
+                        .ok_or_else(|| anyhow::anyhow!("unterminated string."))?;
+                    (CfgToken::Str(&rest[1..end + 1]), end + 2)
+                }
+                c if c == '_' || c.is_alphabetic() => {
+                    let len = rest
+                        .find(|c: char| c != '_' && !c.is_alphanumeric())
+                        .unwrap_or(rest.len());
+                    (CfgToken::Ident(&rest[..len]), len)
+                }
+                c => anyhow::bail!("unexpected `{}`.", c),
+            };
+            tokens.push(token);
+            rest = rest[len..].trim_start();
+        }
+        Ok(tokens)
+    }
+
+    fn parse_predicate(tokens: &mut &[CfgToken<'_>]) -> anyhow::Result<Self> {
+        let name = match tokens.first() {
+            Some(CfgToken::Ident(name)) => *name,
+            token => anyhow::bail!("expected a name, found {:?}.", token),
+        };
+        *tokens = &tokens[1..];
+        match (name, tokens.first()) {
+            ("all", Some(CfgToken::Open))
+            | ("any", Some(CfgToken::Open))
+            | ("not", Some(CfgToken::Open)) => {
+                *tokens = &tokens[1..];
+                let mut predicates = vec![];
+                loop {
+                    if tokens.first() == Some(&CfgToken::Close) {
+                        *tokens = &tokens[1..];
+                        break;
+                    }
+                    predicates.push(Self::parse_predicate(tokens)?);
+                    match tokens.first() {
+                        Some(CfgToken::Comma) => *tokens = &tokens[1..],
+                        Some(CfgToken::Close) => {}
+                        token => anyhow::bail!("expected `,` or `)`, found {:?}.", token),
+                    }
+                }
+                match name {
+                    "all" => Ok(Self::All(predicates)),
+                    "any" => Ok(Self::Any(predicates)),
+                    _ if predicates.len() == 1 => Ok(Self::Not(Box::new(predicates.remove(0)))),
+                    _ => anyhow::bail!("not() takes exactly one predicate."),
+                }
+            }
+            (name, Some(CfgToken::Equals)) => match tokens.get(1) {
+                Some(CfgToken::Str(value)) => {
+                    *tokens = &tokens[2..];
+                    Ok(Self::Option((name.to_owned(), Some((*value).to_owned()))))
+                }
+                token => anyhow::bail!("expected a string, found {:?}.", token),
+            },
+            (name, _) => Ok(Self::Option((name.to_owned(), None))),
+        }
+    }
+
+    fn matches(&self, options: &[CfgOption]) -> bool {
+        match self {
+            Self::Option(option) => options.contains(option),
+            Self::All(predicates) => predicates.iter().all(|cfg| cfg.matches(options)),
+            Self::Any(predicates) => predicates.iter().any(|cfg| cfg.matches(options)),
+            Self::Not(predicate) => !predicate.matches(options),
+        }
     }
-    r
 }
 
-fn llvm_cov_show(
-    llvm_cov: &Path,
-    rustfilt: &Path,
-    profenv: &Profenv,
-    executables: &[PathBuf],
-    html_output: Option<&Path>,
-    ignore: &str,
-) -> anyhow::Result<()> {
-    let output = if let Some(path) = html_output {
-        vec![format!("-output-dir={}", path.to_string_lossy())]
-    } else {
-        vec![]
-    };
+/// Extra flags cargo passes to rustc and rustdoc.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Flags {
+    rustflags: Vec<String>,
+    rustdocflags: Vec<String>,
+    /// Whether the flags need `RUSTC_BOOTSTRAP=1` on a stable toolchain.
+    bootstrap: bool,
+}
 
-    let result = Command::new(llvm_cov)
-        .arg("show")
-        .arg(format!(
-            "-Xdemangler={}",
-            rustfilt.to_string_lossy().as_ref()
-        ))
-        .args(to_obj_args(executables))
-        .arg(format!(
-            "-instr-profile={}",
-            profenv.profdata.to_string_lossy()
-        ))
-        .arg(format!(
-            "-format={}",
-            if html_output.is_some() {
-                "html"
-            } else {
-                "text"
-            }
-        ))
-        .args(output)
-        .arg(format!("-ignore-filename-regex={}", ignore))
-        .arg("-show-instantiations=false")
-        .status()?;
-    if !result.success() {
-        anyhow::bail!("failed to run llvm-cov.");
+impl Flags {
+    fn resolve(
+        config: &CargoConfig,
+        triple: &str,
+        cfgs: &[CfgOption],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            rustflags: resolve_flags(config, triple, cfgs, "rustflags", &env)?,
+            rustdocflags: resolve_flags(config, triple, cfgs, "rustdocflags", &env)?,
+            bootstrap: false,
+        })
+    }
+
+    fn instrumented(mut self, instrumentation: Instrumentation) -> Self {
+        self.rustflags.push(instrumentation.flag().to_owned());
+        self.rustdocflags.push(instrumentation.flag().to_owned());
+        self.bootstrap = instrumentation == Instrumentation::Unstable;
+        self
     }
-    Ok(())
 }
 
-fn llvm_cov_export(
-    llvm_cov: &Path,
-    rustfilt: &Path,
-    profenv: &Profenv,
-    executables: &[PathBuf],
-    output: &Path,
-    ignore: &str,
-) -> anyhow::Result<()> {
-    let result = Command::new(llvm_cov)
-        .arg("export")
-        .arg(format!(
-            "-Xdemangler={}",
-            rustfilt.to_string_lossy().as_ref()
-        ))
-        .args(to_obj_args(executables))
-        .arg(format!(
-            "-instr-profile={}",
-            profenv.profdata.to_string_lossy()
-        ))
-        .arg("-format=lcov")
-        .arg(format!("-ignore-filename-regex={}", ignore))
-        .arg("-show-instantiations=false")
-        .stdout(
-            fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(output)?,
-        )
-        .status()?;
-    if !result.success() {
-        anyhow::bail!("failed to run llvm-cov.");
+/// Same precedence as cargo: `CARGO_ENCODED_<NAME>`, `<NAME>`, then
+/// `target.<triple>.<name>` joined with the matching `target.'cfg(...)'.<name>`,
+/// and `build.<name>` from the config files.
+fn resolve_flags(
+    config: &CargoConfig,
+    triple: &str,
+    cfgs: &[CfgOption],
+    name: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<String>> {
+    let var = name.to_uppercase();
+    if let Some(flags) = env(&format!("CARGO_ENCODED_{}", var)) {
+        return Ok(flags
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .map(ToOwned::to_owned)
+            .collect());
+    }
+    if let Some(flags) = env(&var) {
+        return Ok(flags.split_whitespace().map(ToOwned::to_owned).collect());
+    }
+
+    let mut target_flags = None;
+    if let Some(flags) = config.get(&["target", triple, name]) {
+        target_flags = Some(
+            string_list(flags).with_context(|| format!("invalid target.{}.{}.", triple, name))?,
+        );
+    }
+    for (key, flags) in config.matching_cfgs(cfgs, name)? {
+        let flags =
+            string_list(flags).with_context(|| format!("invalid target.'{}'.{}.", key, name))?;
+        target_flags.get_or_insert_with(Vec::new).extend(flags);
+    }
+    if let Some(flags) = target_flags {
+        return Ok(flags);
+    }
+
+    match config.get(&["build", name]) {
+        Some(flags) => string_list(flags).with_context(|| format!("invalid build.{}.", name)),
+        None => Ok(vec![]),
     }
-    Ok(())
 }
 
-#[derive(Debug, Clap)]
-#[clap(bin_name = "cargo", version = env!("CARGO_PKG_VERSION"), after_long_help = option_env!("RUSTFLAGS").unwrap_or_default())]
-pub enum SubCommand {
-    /// A utility for report LLVM Source-based code coverage.
-    Llvmcov(Opts),
+fn rustc() -> PathBuf {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    rustc.into()
 }
 
-#[derive(Debug, Clap)]
-#[clap(version = env!("CARGO_PKG_VERSION"))]
-pub struct Opts {
-    /// Generate lcov report.
-    #[clap(short = 'l', long, conflicts_with_all = &["html", "lcov-output"])]
-    lcov: bool,
+/// The parts of `rustc -vV` this tool cares about.
+#[derive(Debug, PartialEq, Eq)]
+struct RustcVersion {
+    release: Version,
+    host: String,
+    llvm: Option<String>,
+}
 
-    /// Lcov output file name.
-    #[clap(short = 'L', long)]
-    lcov_output: Option<PathBuf>,
+impl RustcVersion {
+    fn parse(output: &str) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            output
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+                .map(str::trim)
+        };
+        let release =
+            field("release").ok_or_else(|| anyhow::anyhow!("no release found in `rustc -vV`."))?;
+        let release = Version::parse(release)
+            .with_context(|| format!("failed to parse rustc version {}.", release))?;
+        let host = field("host")
+            .ok_or_else(|| anyhow::anyhow!("failed to get host triple."))?
+            .to_owned();
+        let llvm = field("LLVM version").map(ToOwned::to_owned);
+        Ok(Self {
+            release,
+            host,
+            llvm,
+        })
+    }
 
-    /// Generate HTML report.
-    #[clap(short = 'H', long, conflicts_with_all = &["lcov", "lcov-output"])]
-    html: bool,
+    /// `-C instrument-coverage` is stable since 1.60. Before that, the
+    /// unstable `-Z` form exists from 1.47.
+    fn instrumentation(&self) -> anyhow::Result<Instrumentation> {
+        match (self.release.major, self.release.minor) {
+            (1, minor) if minor >= 60 => Ok(Instrumentation::Stable),
+            (1, minor) if minor >= 47 => Ok(Instrumentation::Unstable),
+            _ => anyhow::bail!(
+                "rustc {} is too old. Source-based code coverage needs rustc 1.47 or later.",
+                self.release
+            ),
+        }
+    }
+}
 
-    /// Open HTML report when done.
-    #[clap(short = 'o', long, requires = "html")]
-    open: bool,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instrumentation {
+    /// `-C instrument-coverage`
+    Stable,
+    /// `-Z instrument-coverage`, needs nightly or `RUSTC_BOOTSTRAP=1`.
+    Unstable,
+}
 
-    /// Keep default.profdata & *.profraw
-    #[clap(short = 'k', long)]
-    keep: bool,
+impl Instrumentation {
+    fn flag(self) -> &'static str {
+        match self {
+            Self::Stable => "-Cinstrument-coverage",
+            Self::Unstable => "-Zinstrument-coverage",
+        }
+    }
+}
 
-    /// Verbose output.
-    #[clap(short = 'v', long, parse(from_occurrences))]
-    verbose: usize,
+/// What `cfg(...)` matches when compiling for `triple`.
+fn target_cfgs(rustc: &Path, triple: &str) -> anyhow::Result<Vec<CfgOption>> {
+    let output = Command::new(rustc)
+        .args(["--print", "cfg", "--target", triple])
+        .output()?;
+    if !output.status().success() {
+        anyhow::bail!("failed to run rustc --print cfg.");
+    }
+    Ok(String::from_utf8_lossy(output.stdout())
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => (name.to_owned(), Some(value.trim_matches('"').to_owned())),
+            None => (line.to_owned(), None),
+        })
+        .collect())
 }
 
-fn main() -> anyhow::Result<()> {
-    let opts = SubCommand::parse();
-    let SubCommand::Llvmcov(opts) = opts;
+fn rustc_version(rustc: &Path) -> anyhow::Result<RustcVersion> {
+    let output = Command::new(rustc).arg("-vV").output()?;
+    if !output.status().success() {
+        anyhow::bail!("failed to run rustc -vV.");
+    }
+    RustcVersion::parse(&String::from_utf8_lossy(output.stdout()))
+}
+
+/// The `llvm-tools` component installs into the rustlib directory of the sysroot.
+fn llvm_tools_dir(rustc: &Path, host: &str) -> anyhow::Result<PathBuf> {
+    let output = Command::new(rustc).args(["--print", "sysroot"]).output()?;
+    if !output.status().success() {
+        anyhow::bail!("failed to run rustc --print sysroot.");
+    }
+    let sysroot = String::from_utf8_lossy(output.stdout());
+    Ok(Path::new(sysroot.trim())
+        .join("lib")
+        .join("rustlib")
+        .join(host)
+        .join("bin"))
+}
 
-    stderrlog::new().verbosity(opts.verbose).init()?;
+fn llvm_tool(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}{}", name, env::consts::EXE_SUFFIX))
+}
 
-    let llvm_profdata = Tool::Profdata
-        .path()
-        .context("failed to get llvm-profdata path.")?;
-    if !llvm_profdata.exists() {
-        anyhow::bail!(
-            "No llvm-profdata exists.May be needs `rustup component add llvm-tools{-preview}?`"
-        );
+/// Major version from `LLVM version 11.0.1-rust-1.48.0-stable` or rustc's `11.0`.
+fn llvm_major(version: &str) -> Option<u64> {
+    let version = match version.find("LLVM version ") {
+        Some(pos) => &version[pos + "LLVM version ".len()..],
+        None => version,
+    };
+    version.split('.').next()?.trim().parse().ok()
+}
+
+/// Profiles written by one LLVM major version can't be read by another, and
+/// the resulting errors are hard to trace back to the tool.
+/// Returns the LLVM version of `tool`, if it prints one.
+fn check_llvm_version(tool: &Path, expected: &str) -> anyhow::Result<Option<String>> {
+    // Older llvm-profdata only accepts `--version` after a subcommand.
+    let output = Command::new(tool).args(["show", "--version"]).output()?;
+    if !output.status().success() {
+        anyhow::bail!("failed to run {} show --version.", tool.to_string_lossy());
+    }
+    let output = String::from_utf8_lossy(output.stdout());
+    let version = output
+        .lines()
+        .find_map(|line| line.split_once("LLVM version "))
+        .map(|(_, version)| version.trim().to_owned());
+    match (version.as_deref().and_then(llvm_major), llvm_major(expected)) {
+        (Some(actual), Some(expected)) if actual != expected => anyhow::bail!(
+            "{} is LLVM {}, but rustc uses LLVM {}. Install the matching tools with `rustup component add llvm-tools-preview`, or use `--llvm-path` to point to LLVM {} binaries.",
+            tool.to_string_lossy(),
+            actual,
+            expected,
+            expected
+        ),
+        _ => Ok(version),
     }
-    let llvm_cov = Tool::Cov.path().context("failed to get llvm-cov path.")?;
-    if !llvm_cov.exists() {
+}
+
+/// Absolute path of `name` in the rustup toolchain, bypassing `$CARGO`/`$RUSTC`.
+fn toolchain_binary(toolchain: &str, name: &str) -> anyhow::Result<PathBuf> {
+    let output = Command::new("rustup")
+        .args(["which", "--toolchain", toolchain, name])
+        .output()
+        .context("failed to run rustup.")?;
+    if !output.status().success() {
         anyhow::bail!(
-            "No llvm-cov exists.May be needs `rustup component add llvm-tools{-preview}?`"
+            "failed to find {} of toolchain {}. May be needs `rustup toolchain install {}`.",
+            name,
+            toolchain,
+            toolchain
         );
     }
-    let rustfilt = which::which("rustfilt")
-        .context("No rustfilt exists.May be needs `cargo install rustfilt`.")?;
+    Ok(String::from_utf8_lossy(output.stdout()).trim().into())
+}
 
-    let cargo = cargo();
-    let target = target_dir(&cargo)
-        .context("failed to get target directory.")?
-        .join("cov");
-    fs::create_dir_all(&target)
-        .with_context(|| format!("failed to create {}", target.to_string_lossy()))?;
-    let profenv = Profenv::new(&target)?;
-    let executables = build(&cargo, &target, &profenv).context("failed to build executables.")?;
+fn cargo() -> PathBuf {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    cargo.into()
+}
 
-    log::debug!("cargo binary: {:?}", cargo);
-    log::debug!("output directory: {:?}", target);
-    log::debug!("llvm-profdata: {:?}", llvm_profdata);
-    log::debug!("executables: {:?}", executables);
-    log::debug!("LLVM_PROFILE_FILE: {:?}", profenv.profraw());
+fn cargo_home() -> String {
+    env::var("CARGO_HOME").unwrap_or_default()
+}
 
-    for executable in &executables {
-        run_test(&executable, &profenv)?;
-    }
+fn rustup_home() -> String {
+    env::var("RUSTUP_HOME").unwrap_or_default()
+}
 
-    merge_profdata(&llvm_profdata, &profenv)?;
+/// Exported by `show-env` along with `CARGO_TARGET_DIR`, which makes cargo
+/// report the coverage directory itself as the target directory.
+const SHOW_ENV_TARGET_DIR: &str = "CARGO_LLVMCOV_TARGET_DIR";
 
-    let cargo_home = cargo_home();
-    let rustup_home = rustup_home();
-    let ignore = format!("{}|{}", cargo_home, rustup_home);
+fn target_dir(metadata: &Metadata) -> PathBuf {
+    coverage_dir(&metadata.target_directory, |key| env::var(key).ok())
+}
 
-    log::debug!("generating report..");
-    match opts {
-        Opts { lcov: true, .. } => {
-            llvm_cov_export(
-                &llvm_cov,
-                &rustfilt,
-                &profenv,
-                &executables,
+fn coverage_dir(target_directory: &Path, env: impl Fn(&str) -> Option<String>) -> PathBuf {
+    match env(SHOW_ENV_TARGET_DIR) {
+        Some(dir) if Path::new(&dir) == target_directory => dir.into(),
+        _ => target_directory.join("cov"),
+    }
+}
+
+/// Removes the whole coverage target directory, or with `keep_build` only
+/// profiles and reports.
+fn clean(target: &Path, keep_build: bool) -> anyhow::Result<()> {
+    let paths = if keep_build {
+        let mut paths = Profenv::leftovers(target)?;
+        paths.push(target.join("html"));
+        paths.push(target.join("cov.info"));
+        paths
+    } else {
+        vec![target.to_owned()]
+    };
+
+    for path in paths {
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else if path.exists() {
+            fs::remove_file(&path)
+        } else {
+            continue;
+        };
+        result.with_context(|| format!("failed to remove {}", path.to_string_lossy()))?;
+        log::info!("removed {}", path.to_string_lossy());
+    }
+    Ok(())
+}
+
+fn metadata(cargo: &Path) -> anyhow::Result<Metadata> {
+    let metadata = Command::new(cargo).arg("metadata").output()?;
+    let metadata = serde_json::from_slice::<Metadata>(metadata.stdout())?;
+    Ok(metadata)
+}
+
+fn selected_packages<'a>(
+    metadata: &'a Metadata,
+    opts: &BuildOpts,
+) -> anyhow::Result<Vec<&'a Package>> {
+    let members = metadata
+        .workspace_members
+        .iter()
+        .map(|id| &metadata[id])
+        .collect::<Vec<_>>();
+
+    if opts.workspace {
+        for name in &opts.exclude {
+            if !members.iter().any(|p| &p.name == name) {
+                anyhow::bail!("excluded package `{}` not found in workspace.", name);
+            }
+        }
+        return Ok(members
+            .into_iter()
+            .filter(|p| !opts.exclude.contains(&p.name))
+            .collect());
+    }
+
+    if !opts.package.is_empty() {
+        let mut selected = vec![];
+        for name in &opts.package {
+            match members.iter().find(|p| &p.name == name) {
+                Some(p) => selected.push(*p),
+                None => anyhow::bail!("package `{}` not found in workspace.", name),
+            }
+        }
+        return Ok(selected);
+    }
+
+    match metadata.root_package() {
+        Some(root) => Ok(vec![root]),
+        None => Ok(members),
+    }
+}
+
+fn escape_regex(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            r.push('\\');
+        }
+        r.push(c);
+    }
+    r
+}
+
+fn ignore_regex(homes: &[String], metadata: &Metadata, selected: &[&Package]) -> String {
+    let selected_dirs = selected
+        .iter()
+        .filter_map(|p| p.manifest_path.parent())
+        .collect::<Vec<_>>();
+
+    let mut ignore = homes
+        .iter()
+        .filter(|home| !home.is_empty())
+        .map(|home| escape_regex(home))
+        .collect::<Vec<_>>();
+
+    // Ignore source directories of unselected members, but never a directory
+    // containing a selected member (e.g. a root package of the workspace).
+    for id in &metadata.workspace_members {
+        let member = &metadata[id];
+        if selected.iter().any(|p| p.id == member.id) {
+            continue;
+        }
+        for target in &member.targets {
+            if target.kind.iter().any(|k| k == "custom-build") {
+                continue;
+            }
+            let dir = match target.src_path.parent() {
+                Some(dir) => dir,
+                None => continue,
+            };
+            if selected_dirs.iter().any(|s| s.starts_with(dir)) {
+                continue;
+            }
+            let dir = escape_regex(&format!("{}/", dir.to_string_lossy()));
+            if !ignore.contains(&dir) {
+                ignore.push(dir);
+            }
+        }
+    }
+
+    ignore.join("|")
+}
+
+#[derive(Debug, Default)]
+struct Artifacts {
+    executables: Vec<PathBuf>,
+    /// Activated features keyed by package id.
+    features: BTreeMap<String, Vec<String>>,
+}
+
+fn build(
+    cargo: &Path,
+    target: &Path,
+    profenv: &Profenv,
+    opts: &BuildOpts,
+    targets: &TargetOpts,
+    flags: &Flags,
+) -> anyhow::Result<Artifacts> {
+    let mut command = Command::new(cargo);
+    command
+        .arg("test")
+        .arg("--no-run")
+        .arg("--message-format")
+        .arg("json")
+        .args(targets.args())
+        .arg("--target-dir")
+        .arg(target)
+        .args(opts.args());
+    collect_artifacts(&mut command, profenv, flags, |target, profile| {
+        profile.test && targets.wants(target)
+    })
+}
+
+fn build_bin(
+    cargo: &Path,
+    target: &Path,
+    profenv: &Profenv,
+    opts: &BuildOpts,
+    bin: Option<&str>,
+    flags: &Flags,
+) -> anyhow::Result<Artifacts> {
+    let bin_args = match bin {
+        Some(bin) => vec!["--bin", bin],
+        None => vec!["--bins"],
+    };
+    let mut command = Command::new(cargo);
+    command
+        .arg("build")
+        .arg("--message-format")
+        .arg("json")
+        .args(bin_args)
+        .arg("--target-dir")
+        .arg(target)
+        .args(opts.args());
+    let artifacts = collect_artifacts(&mut command, profenv, flags, |target, profile| {
+        !profile.test && target.kind.iter().any(|kind| kind == "bin")
+    })?;
+
+    if artifacts.executables.len() > 1 {
+        anyhow::bail!(
+            "could not determine which binary to run. Use the `--bin` option to specify a binary."
+        );
+    }
+    Ok(artifacts)
+}
+
+/// Environment of instrumented builds and the processes they run.
+fn instrument_env(flags: &Flags, profraw: &Path) -> Vec<(&'static str, String)> {
+    let mut env = vec![];
+    if flags.bootstrap {
+        env.push(("RUSTC_BOOTSTRAP", "1".to_owned()));
+    }
+    env.push(("RUSTFLAGS", flags.rustflags.join(" ")));
+    env.push(("CARGO_ENCODED_RUSTFLAGS", flags.rustflags.join("\x1f")));
+    env.push(("LLVM_PROFILE_FILE", profraw.to_string_lossy().into_owned()));
+    env
+}
+
+/// `export` lines for `eval`.
+/// Builds into the coverage directory, so that `report` finds the executables
+/// next to the profiles.
+fn show_env(flags: &Flags, target: &Path, toolchain: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut env = instrument_env(flags, &Profenv::external_profraw(target));
+    env.push(("CARGO_TARGET_DIR", target.to_string_lossy().into_owned()));
+    env.push((SHOW_ENV_TARGET_DIR, target.to_string_lossy().into_owned()));
+    if let Some(toolchain) = toolchain {
+        env.push(("RUSTUP_TOOLCHAIN", toolchain.to_owned()));
+    }
+    env
+}
+
+fn env_script(env: &[(&str, String)]) -> String {
+    env.iter()
+        .map(|(key, value)| format!("export {}='{}'\n", key, value.replace('\'', r"'\''")))
+        .collect()
+}
+
+fn collect_artifacts(
+    command: &mut Command,
+    profenv: &Profenv,
+    flags: &Flags,
+    filter: impl Fn(&BuildTarget, &BuildProfile) -> bool,
+) -> anyhow::Result<Artifacts> {
+    for (key, value) in instrument_env(flags, &profenv.profraw()) {
+        command.env(key, value);
+    }
+    let mut build_proc = command.stdout(Stdio::piped()).spawn()?;
+
+    let mut artifacts = Artifacts::default();
+    let mut errors = vec![];
+
+    let stdout = build_proc.take_stdout();
+    let stdout = BufReader::new(stdout);
+    for line in stdout.lines() {
+        let line = line?;
+        let line = serde_json::from_str::<BuildMessage>(&line)
+            .with_context(|| format!("failed to parse cargo message: {}", line))?;
+        match line {
+            BuildMessage::CompilerArtifact {
+                package_id,
+                features,
+                target,
+                profile,
+                executable,
+                ..
+            } => {
+                if let Some(exe) = executable {
+                    if filter(&target, &profile) {
+                        artifacts.executables.push(exe);
+                    }
+                }
+                artifacts.features.insert(package_id, features);
+            }
+            BuildMessage::CompilerMessage { message } => {
+                if let Some(rendered) = &message.rendered {
+                    eprint!("{}", rendered);
+                    if message.is_error() {
+                        errors.push(rendered.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let r = build_proc.wait()?;
+    if !r.success() {
+        if errors.is_empty() {
+            anyhow::bail!("failed to run cargo build.");
+        }
+        anyhow::bail!(
+            "failed to run cargo build.\n\n{}",
+            errors.concat().trim_end()
+        );
+    }
+
+    if artifacts.executables.is_empty() {
+        anyhow::bail!("no executable found.")
+    }
+    Ok(artifacts)
+}
+
+fn report_title(selected: &[&Package], artifacts: &Artifacts) -> String {
+    selected
+        .iter()
+        .map(|p| match artifacts.features.get(&p.id.repr) {
+            Some(features) if !features.is_empty() => {
+                format!("{} (features: {})", p.name, features.join(", "))
+            }
+            _ => format!("{} (no features)", p.name),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn lcov_test_name(title: &str) -> String {
+    title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn run_test(
+    prog: &Path,
+    harness: &HarnessOpts,
+    runner: &[String],
+    profenv: &Profenv,
+) -> anyhow::Result<()> {
+    execute(prog, &harness.args(), runner, profenv)
+}
+
+fn run_tests(
+    executables: &[PathBuf],
+    harness: &HarnessOpts,
+    runner: &[String],
+    profenv: &Profenv,
+) -> anyhow::Result<()> {
+    run_tests_to(
+        executables,
+        harness,
+        runner,
+        profenv,
+        &mut io::stdout(),
+        &mut io::stderr(),
+    )
+}
+
+/// `run_tests`, printing the output of parallel runs to `stdout` and `stderr`.
+fn run_tests_to(
+    executables: &[PathBuf],
+    harness: &HarnessOpts,
+    runner: &[String],
+    profenv: &Profenv,
+    stdout: &mut (dyn Write + Send),
+    stderr: &mut (dyn Write + Send),
+) -> anyhow::Result<()> {
+    if harness.jobs <= 1 {
+        for executable in executables {
+            run_test(executable, harness, runner, profenv)?;
+        }
+        return Ok(());
+    }
+
+    // Each process writes its own `%p` profraw, so only the output needs care:
+    // it is captured and printed in one piece once the process exits.
+    let print = Mutex::new((stdout, stderr));
+    let args = harness.args();
+    let results = parallel(executables, harness.jobs, |executable| {
+        let output = executable_command(executable, &args, runner, profenv).output()?;
+        {
+            let mut print = print.lock().unwrap_or_else(|e| e.into_inner());
+            print.0.write_all(output.stdout())?;
+            print.1.write_all(output.stderr())?;
+        }
+        Ok::<_, io::Error>(output.status().success())
+    });
+
+    let mut failed = false;
+    for (executable, result) in executables.iter().zip(results) {
+        if !result? {
+            log::error!("{} failed.", executable.to_string_lossy());
+            failed = true;
+        }
+    }
+    if failed {
+        anyhow::bail!("failed to run executable.");
+    }
+    Ok(())
+}
+
+/// Applies `f` to every item on up to `jobs` threads. Results keep the order of `items`.
+fn parallel<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let item = match items.get(i) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let result = f(item);
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|result| result.expect("worker thread panicked."))
+        .collect()
+}
+
+fn executable_command(
+    prog: &Path,
+    args: &[String],
+    runner: &[String],
+    profenv: &Profenv,
+) -> Command {
+    let mut command = match runner.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args).arg(prog);
+            command
+        }
+        None => Command::new(prog),
+    };
+    command
+        .args(args)
+        .env("LLVM_PROFILE_FILE", profenv.profraw());
+    command
+}
+
+fn execute(
+    prog: &Path,
+    args: &[String],
+    runner: &[String],
+    profenv: &Profenv,
+) -> anyhow::Result<()> {
+    let r = executable_command(prog, args, runner, profenv).status()?;
+    if !r.success() {
+        anyhow::bail!("failed to run executable.");
+    }
+    Ok(())
+}
+
+fn run_doctests(
+    cargo: &Path,
+    target: &Path,
+    profenv: &Profenv,
+    opts: &BuildOpts,
+    harness: &HarnessOpts,
+    flags: &Flags,
+) -> anyhow::Result<Vec<PathBuf>> {
+    // Binaries left over from a previous run must not end up in the report.
+    let doctests_dir = target.join("doctests");
+    if doctests_dir.exists() {
+        fs::remove_dir_all(&doctests_dir)?;
+    }
+    fs::create_dir_all(&doctests_dir)?;
+
+    let mut rustdocflags = flags.rustdocflags.clone();
+    rustdocflags.push("-Zunstable-options".to_owned());
+    rustdocflags.push("--persist-doctests".to_owned());
+    rustdocflags.push(doctests_dir.to_string_lossy().into_owned());
+
+    let r = Command::new(cargo)
+        .arg("test")
+        .arg("--doc")
+        .arg("--target-dir")
+        .arg(target)
+        .args(opts.args())
+        .arg("--")
+        .args(harness.args())
+        .env("RUSTC_BOOTSTRAP", "1")
+        .env("RUSTFLAGS", flags.rustflags.join(" "))
+        .env("CARGO_ENCODED_RUSTFLAGS", flags.rustflags.join("\x1f"))
+        .env("RUSTDOCFLAGS", rustdocflags.join(" "))
+        .env("CARGO_ENCODED_RUSTDOCFLAGS", rustdocflags.join("\x1f"))
+        .env("LLVM_PROFILE_FILE", profenv.profraw())
+        .status()?;
+    if !r.success() {
+        anyhow::bail!("failed to run cargo test --doc.");
+    }
+
+    doctest_executables(&doctests_dir)
+}
+
+fn doctest_executables(doctests_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let pattern = doctests_dir.join("*").join("rust_out*");
+    let mut result = vec![];
+    for path in glob::glob(pattern.to_string_lossy().as_ref())? {
+        let path = path?;
+        match path.extension() {
+            Some(ext) if ext != "exe" => {}
+            _ => result.push(path),
+        }
+    }
+    Ok(result)
+}
+
+fn merge_profdata(llvm_profdata: &Path, profenv: &Profenv) -> anyhow::Result<()> {
+    merge_profiles(llvm_profdata, &profenv.profraw_files()?, &profenv.profdata)
+}
+
+fn merge_profiles(llvm_profdata: &Path, profiles: &[PathBuf], output: &Path) -> anyhow::Result<()> {
+    let result = Command::new(llvm_profdata)
+        .arg("merge")
+        .arg("-sparse")
+        .args(profiles)
+        .arg("-o")
+        .arg(output)
+        .status()?;
+    if !result.success() {
+        anyhow::bail!("failed to run llvm-profdata.");
+    }
+    Ok(())
+}
+
+/// A `merge` input: a kept profdata file, a profraw directory, or a kept
+/// coverage directory. The manifest sits next to the profiles.
+fn merge_input(path: &Path) -> anyhow::Result<(Vec<PathBuf>, Manifest)> {
+    let parent = path.parent().unwrap_or(path);
+    let (dir, profiles) = if path.is_file() {
+        (parent, vec![path.to_owned()])
+    } else if path.join("default.profdata").exists() {
+        (path, vec![path.join("default.profdata")])
+    } else {
+        let mut profiles = vec![];
+        for pattern in &[path.join("*.profraw"), path.join("profraw-*/*.profraw")] {
+            for profile in glob::glob(pattern.to_string_lossy().as_ref())? {
+                profiles.push(profile?);
+            }
+        }
+        if Manifest::path(path).exists() {
+            (path, profiles)
+        } else {
+            (parent, profiles)
+        }
+    };
+    if profiles.is_empty() {
+        anyhow::bail!("no profdata or profraw found.");
+    }
+    Ok((profiles, Manifest::load(&Manifest::path(dir))?))
+}
+
+/// Instrumented executables in `target`, for profiles written by processes
+/// this tool did not build.
+fn built_executables(target: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut executables = vec![];
+    for pattern in &[target.join("*/deps/*"), target.join("*/*/deps/*")] {
+        for path in glob::glob(pattern.to_string_lossy().as_ref())? {
+            let path = path?;
+            // Skips rlibs, proc-macro dylibs and dep-info files.
+            if path.is_file() && path.extension().unwrap_or_default() == env::consts::EXE_EXTENSION
+            {
+                executables.push(path);
+            }
+        }
+    }
+    Ok(executables)
+}
+
+/// The manifest kept by `--keep`, or one made up for the profiles of
+/// processes set up by `show-env`.
+fn report_manifest(tools: &Tools, metadata: &Metadata, target: &Path) -> anyhow::Result<Manifest> {
+    let profdata = target.join("default.profdata");
+    let manifest = Manifest::path(target);
+    let profraw_env = Profenv::external_profraw_dir(target);
+    let pattern = profraw_env.join("*.profraw");
+    let profiles =
+        glob::glob(pattern.to_string_lossy().as_ref())?.collect::<Result<Vec<_>, _>>()?;
+
+    if manifest.exists() {
+        if !profiles.is_empty() {
+            anyhow::bail!(
+                "found both kept coverage data ({}) and profiles of `show-env` processes ({}). Remove the one not to report.",
+                manifest.to_string_lossy(),
+                profraw_env.to_string_lossy()
+            );
+        }
+        if !profdata.exists() {
+            anyhow::bail!("no kept coverage data found. Run with `--keep` first.");
+        }
+        return Manifest::load(&manifest);
+    }
+
+    if profiles.is_empty() {
+        anyhow::bail!(
+            "no coverage data found. Run with `--keep` or set up processes with `show-env` first."
+        );
+    }
+    merge_profiles(&tools.llvm_profdata, &profiles, &profdata)?;
+
+    let executables = built_executables(target)?;
+    if executables.is_empty() {
+        anyhow::bail!("no executable found.");
+    }
+    let selected = selected_packages(metadata, &BuildOpts::default())?;
+    Ok(Manifest {
+        executables,
+        title: selected
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>()
+            .join("; "),
+        ignore: ignore_regex(&[cargo_home(), rustup_home()], metadata, &selected),
+    })
+}
+
+/// Shards may check out to different paths, so executables are compared by
+/// file name.
+fn executable_names(manifest: &Manifest) -> BTreeSet<String> {
+    manifest
+        .executables
+        .iter()
+        .filter_map(|exe| exe.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Merges the inputs into `target` as if they were kept by one run.
+fn merge(llvm_profdata: &Path, inputs: &[PathBuf], target: &Path) -> anyhow::Result<Manifest> {
+    let mut profiles = vec![];
+    let mut merged: Option<(&PathBuf, Manifest)> = None;
+    for input in inputs {
+        let (mut files, manifest) = merge_input(input)
+            .with_context(|| format!("invalid merge input {}.", input.to_string_lossy()))?;
+        if let Some(exe) = manifest.executables.iter().find(|exe| !exe.exists()) {
+            anyhow::bail!(
+                "executable {} of {} not found. Merge inputs need the executables of their run.",
+                exe.to_string_lossy(),
+                input.to_string_lossy()
+            );
+        }
+        profiles.append(&mut files);
+        match &merged {
+            Some((first, expected)) => {
+                let expected = executable_names(expected);
+                let actual = executable_names(&manifest);
+                if expected != actual {
+                    let diff = expected
+                        .symmetric_difference(&actual)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    anyhow::bail!(
+                        "executables of {} do not match {}: {}",
+                        input.to_string_lossy(),
+                        first.to_string_lossy(),
+                        diff.join(", ")
+                    );
+                }
+            }
+            None => merged = Some((input, manifest)),
+        }
+    }
+    let (_, manifest) = merged.ok_or_else(|| anyhow::anyhow!("nothing to merge."))?;
+
+    merge_profiles(llvm_profdata, &profiles, &target.join("default.profdata"))?;
+    manifest.save(&Manifest::path(target))?;
+    Ok(manifest)
+}
+
+fn ignore_args(ignore: &str) -> Option<String> {
+    if ignore.is_empty() {
+        None
+    } else {
+        Some(format!("-ignore-filename-regex={}", ignore))
+    }
+}
+
+fn to_obj_args<'a>(executables: &'a [PathBuf]) -> Vec<&'a OsStr> {
+    let mut r = vec![];
+    let mut iter = executables.iter();
+    if let Some(exe) = iter.next() {
+        r.push(exe.as_ref());
+    }
+    for exe in iter {
+        r.push("-object".as_ref());
+        r.push(exe.as_ref());
+    }
+    r
+}
+
+/// `llvm-cov` feeds one symbol per line and expects one name per line back.
+fn demangle(input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        match rustc_demangle::try_demangle(line.trim()) {
+            Ok(name) => writeln!(output, "{:#}", name)?,
+            Err(_) => writeln!(output, "{}", line)?,
+        }
+    }
+    output.flush()
+}
+
+/// `-Xdemangler` arguments that run the hidden `demangle` subcommand of this binary.
+fn demangler() -> anyhow::Result<Vec<String>> {
+    let exe = env::current_exe().context("failed to get current executable.")?;
+    Ok(vec![
+        exe.to_string_lossy().into_owned(),
+        "llvmcov".to_owned(),
+        "demangle".to_owned(),
+    ])
+}
+
+fn llvm_cov_show(
+    llvm_cov: &Path,
+    demangler: &[String],
+    profdata: &Path,
+    executables: &[PathBuf],
+    html_output: Option<&Path>,
+    ignore: &str,
+    title: &str,
+) -> anyhow::Result<()> {
+    let output = if let Some(path) = html_output {
+        vec![format!("-output-dir={}", path.to_string_lossy())]
+    } else {
+        // llvm-cov only renders the project title into files.
+        println!("{}", title);
+        vec![]
+    };
+
+    let result = Command::new(llvm_cov)
+        .arg("show")
+        .args(demangler.iter().map(|arg| format!("-Xdemangler={}", arg)))
+        .args(to_obj_args(executables))
+        .arg(format!("-instr-profile={}", profdata.to_string_lossy()))
+        .arg(format!(
+            "-format={}",
+            if html_output.is_some() {
+                "html"
+            } else {
+                "text"
+            }
+        ))
+        .args(output)
+        .arg(format!("-project-title={}", title))
+        .args(ignore_args(ignore))
+        .arg("-show-instantiations=false")
+        .status()?;
+    if !result.success() {
+        anyhow::bail!("failed to run llvm-cov.");
+    }
+    Ok(())
+}
+
+/// Print a per-file table of region, function, line and branch coverage.
+fn llvm_cov_report(
+    llvm_cov: &Path,
+    profdata: &Path,
+    executables: &[PathBuf],
+    ignore: &str,
+    title: &str,
+) -> anyhow::Result<()> {
+    println!("{}", title);
+
+    let result = Command::new(llvm_cov)
+        .arg("report")
+        .args(to_obj_args(executables))
+        .arg(format!("-instr-profile={}", profdata.to_string_lossy()))
+        .args(ignore_args(ignore))
+        .status()?;
+    if !result.success() {
+        anyhow::bail!("failed to run llvm-cov.");
+    }
+    Ok(())
+}
+
+fn llvm_cov_export(
+    llvm_cov: &Path,
+    demangler: &[String],
+    profdata: &Path,
+    executables: &[PathBuf],
+    output: &Path,
+    ignore: &str,
+    title: &str,
+) -> anyhow::Result<()> {
+    let mut output = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(output)?;
+    writeln!(output, "TN:{}", lcov_test_name(title))?;
+
+    let result = Command::new(llvm_cov)
+        .arg("export")
+        .args(demangler.iter().map(|arg| format!("-Xdemangler={}", arg)))
+        .args(to_obj_args(executables))
+        .arg(format!("-instr-profile={}", profdata.to_string_lossy()))
+        .arg("-format=lcov")
+        .args(ignore_args(ignore))
+        .arg("-show-instantiations=false")
+        .stdout(output)
+        .status()?;
+    if !result.success() {
+        anyhow::bail!("failed to run llvm-cov.");
+    }
+    Ok(())
+}
+
+/// Runs `llvm-cov export -format=text` and parses its output.
+fn llvm_cov_export_text(
+    llvm_cov: &Path,
+    demangler: &[String],
+    profdata: &Path,
+    executables: &[PathBuf],
+    ignore: &str,
+) -> anyhow::Result<export::Export> {
+    let output = Command::new(llvm_cov)
+        .arg("export")
+        .args(demangler.iter().map(|arg| format!("-Xdemangler={}", arg)))
+        .args(to_obj_args(executables))
+        .arg(format!("-instr-profile={}", profdata.to_string_lossy()))
+        .arg("-format=text")
+        .args(ignore_args(ignore))
+        .arg("-show-instantiations=false")
+        .output()?;
+    if !output.status().success() {
+        anyhow::bail!("failed to run llvm-cov.");
+    }
+    serde_json::from_slice(output.stdout()).context("failed to parse llvm-cov export.")
+}
+
+#[derive(Debug, Clap)]
+#[clap(bin_name = "cargo", version = env!("CARGO_PKG_VERSION"), after_long_help = option_env!("RUSTFLAGS").unwrap_or_default())]
+pub enum SubCommand {
+    /// A utility for report LLVM Source-based code coverage.
+    Llvmcov(Opts),
+}
+
+#[derive(Debug, Default, Clap)]
+pub struct ToolchainOpts {
+    /// Use the rustup toolchain for cargo, rustc and the LLVM tools.
+    #[clap(long, value_name = "TOOLCHAIN")]
+    toolchain: Option<String>,
+
+    /// Directory containing llvm-profdata and llvm-cov matching rustc's LLVM.
+    #[clap(long, value_name = "DIR")]
+    llvm_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clap)]
+pub struct BuildOpts {
+    /// Package to measure coverage for.
+    #[clap(short = 'p', long, number_of_values = 1, conflicts_with = "workspace")]
+    package: Vec<String>,
+
+    /// Measure coverage for all packages in the workspace.
+    #[clap(long)]
+    workspace: bool,
+
+    /// Exclude packages from the workspace.
+    #[clap(long, number_of_values = 1, requires = "workspace")]
+    exclude: Vec<String>,
+
+    /// Build for the target triple.
+    #[clap(long, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    #[clap(flatten)]
+    tools: ToolchainOpts,
+
+    /// Space or comma separated list of features to activate.
+    #[clap(long, number_of_values = 1)]
+    features: Vec<String>,
+
+    /// Activate all available features.
+    #[clap(long)]
+    all_features: bool,
+
+    /// Do not activate the `default` feature.
+    #[clap(long)]
+    no_default_features: bool,
+}
+
+impl BuildOpts {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for package in &self.package {
+            args.push("--package".to_owned());
+            args.push(package.clone());
+        }
+        if self.workspace {
+            args.push("--workspace".to_owned());
+        }
+        for exclude in &self.exclude {
+            args.push("--exclude".to_owned());
+            args.push(exclude.clone());
+        }
+        if let Some(target) = &self.target {
+            args.push("--target".to_owned());
+            args.push(target.clone());
+        }
+        for features in &self.features {
+            args.push("--features".to_owned());
+            args.push(features.clone());
+        }
+        if self.all_features {
+            args.push("--all-features".to_owned());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".to_owned());
+        }
+        args
+    }
+}
+
+#[derive(Debug, Default, Clap)]
+pub struct TargetOpts {
+    /// Measure coverage for the library's unit tests.
+    #[clap(long)]
+    lib: bool,
+
+    /// Measure coverage for all binaries' unit tests.
+    #[clap(long)]
+    bins: bool,
+
+    /// Measure coverage for the specified integration test.
+    #[clap(long, number_of_values = 1)]
+    test: Vec<String>,
+
+    /// Measure coverage for all targets that have `test = true` (default).
+    #[clap(long)]
+    tests: bool,
+
+    /// Measure coverage for all examples built in test mode.
+    #[clap(long)]
+    examples: bool,
+
+    /// Measure coverage for all benchmarks built in test mode.
+    #[clap(long)]
+    benches: bool,
+
+    /// Also measure coverage for doctests (requires nightly rustdoc).
+    #[clap(long)]
+    doctests: bool,
+}
+
+impl TargetOpts {
+    fn has_selection(&self) -> bool {
+        self.lib
+            || self.bins
+            || !self.test.is_empty()
+            || self.tests
+            || self.examples
+            || self.benches
+    }
+
+    fn args(&self) -> Vec<String> {
+        if !self.has_selection() {
+            return vec!["--tests".to_owned()];
+        }
+
+        let mut args = vec![];
+        if self.lib {
+            args.push("--lib".to_owned());
+        }
+        if self.bins {
+            args.push("--bins".to_owned());
+        }
+        for test in &self.test {
+            args.push("--test".to_owned());
+            args.push(test.clone());
+        }
+        if self.tests {
+            args.push("--tests".to_owned());
+        }
+        if self.examples {
+            args.push("--examples".to_owned());
+        }
+        if self.benches {
+            args.push("--benches".to_owned());
+        }
+        args
+    }
+
+    /// Whether an executable built in test mode for `target` belongs to the selection.
+    fn wants(&self, target: &BuildTarget) -> bool {
+        if !self.has_selection() {
+            return target.test;
+        }
+        if self.tests && target.test {
+            return true;
+        }
+
+        target.kind.iter().any(|kind| match kind.as_str() {
+            "lib" | "rlib" | "dylib" | "cdylib" | "staticlib" | "proc-macro" => self.lib,
+            "bin" => self.bins,
+            "test" => self.test.contains(&target.name),
+            "example" => self.examples,
+            "bench" => self.benches,
+            _ => false,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clap)]
+pub struct HarnessOpts {
+    /// Do not pass `--nocapture` to the test executables.
+    #[clap(long)]
+    capture: bool,
+
+    /// Number of test executables to run in parallel.
+    #[clap(long, value_name = "N", default_value = "1")]
+    jobs: usize,
+
+    /// Arguments for the test executables.
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+impl HarnessOpts {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.capture {
+            args.push("--nocapture".to_owned());
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+#[derive(Debug, Default, Clap)]
+pub struct ReportOpts {
+    /// Generate lcov report.
+    #[clap(short = 'l', long, conflicts_with_all = &["html", "lcov-output"])]
+    lcov: bool,
+
+    /// Lcov output file name.
+    #[clap(short = 'L', long)]
+    lcov_output: Option<PathBuf>,
+
+    /// Generate HTML report.
+    #[clap(short = 'H', long, conflicts_with_all = &["lcov", "lcov-output"])]
+    html: bool,
+
+    /// Write the coverage data as JSON to the file.
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["lcov", "lcov-output", "html"])]
+    json: Option<PathBuf>,
+
+    /// Write a Cobertura XML report to the file.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json"]
+    )]
+    cobertura: Option<PathBuf>,
+
+    /// Write a SonarQube generic coverage report to the file.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json", "cobertura"]
+    )]
+    sonar: Option<PathBuf>,
+
+    /// Write a Codecov JSON report to the file.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json", "cobertura", "sonar"]
+    )]
+    codecov: Option<PathBuf>,
+
+    /// Write a Coveralls JSON report to the file.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json", "cobertura", "sonar", "codecov"]
+    )]
+    coveralls: Option<PathBuf>,
+
+    /// Print a per-file coverage summary (default).
+    #[clap(
+        long,
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json", "cobertura", "sonar", "codecov", "coveralls"]
+    )]
+    summary: bool,
+
+    /// Print the annotated source listing instead of the summary.
+    #[clap(
+        long,
+        conflicts_with_all = &["lcov", "lcov-output", "html", "json", "cobertura", "sonar", "codecov", "coveralls", "summary"]
+    )]
+    text: bool,
+
+    /// Open HTML report when done.
+    #[clap(short = 'o', long, requires = "html")]
+    open: bool,
+}
+
+#[derive(Debug, Clap)]
+#[clap(version = env!("CARGO_PKG_VERSION"), setting = AppSettings::ArgsNegateSubcommands)]
+pub struct Opts {
+    #[clap(flatten)]
+    report: ReportOpts,
+
+    /// Keep default.profdata & *.profraw for the `report` and `merge` subcommands
+    #[clap(short = 'k', long)]
+    keep: bool,
+
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    build: BuildOpts,
+
+    #[clap(flatten)]
+    targets: TargetOpts,
+
+    #[clap(flatten)]
+    harness: HarnessOpts,
+
+    #[clap(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Debug, Clap)]
+#[allow(clippy::large_enum_variant)]
+pub enum Action {
+    /// Run a binary target and report its coverage.
+    Run(RunOpts),
+
+    /// Regenerate the report from the data kept by `--keep` or written by
+    /// processes set up with `show-env`.
+    Report(ReportCommandOpts),
+
+    /// Print the environment of instrumented builds for `eval`.
+    ShowEnv(ShowEnvOpts),
+
+    /// Merge kept coverage data of several runs into one report.
+    Merge(MergeOpts),
+
+    /// Remove the coverage target directory.
+    Clean(CleanOpts),
+
+    /// Check the prerequisites and print what was found.
+    Doctor(DoctorOpts),
+
+    /// Demangle symbols from stdin for `llvm-cov -Xdemangler`.
+    #[clap(setting = AppSettings::Hidden)]
+    Demangle,
+}
+
+#[derive(Debug, Clap)]
+#[clap(version = env!("CARGO_PKG_VERSION"))]
+pub struct RunOpts {
+    #[clap(flatten)]
+    report: ReportOpts,
+
+    /// Keep default.profdata & *.profraw for the `report` and `merge` subcommands
+    #[clap(short = 'k', long)]
+    keep: bool,
+
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    build: BuildOpts,
+
+    /// Name of the bin target to run.
+    #[clap(long)]
+    bin: Option<String>,
+
+    /// Arguments for the binary.
+    #[clap(last = true)]
+    args: Vec<String>,
+}
+
+#[derive(Debug, Clap)]
+pub struct ReportCommandOpts {
+    #[clap(flatten)]
+    report: ReportOpts,
+
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    tools: ToolchainOpts,
+}
+
+#[derive(Debug, Clap)]
+pub struct ShowEnvOpts {
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    tools: ToolchainOpts,
+
+    /// Build for the target triple.
+    #[clap(long, value_name = "TRIPLE")]
+    target: Option<String>,
+}
+
+#[derive(Debug, Clap)]
+pub struct MergeOpts {
+    #[clap(flatten)]
+    report: ReportOpts,
+
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    tools: ToolchainOpts,
+
+    /// Kept profdata files, profraw directories or coverage directories.
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clap)]
+pub struct DoctorOpts {
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    #[clap(flatten)]
+    tools: ToolchainOpts,
+}
+
+#[derive(Debug, Clap)]
+pub struct CleanOpts {
+    /// Verbose output.
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: usize,
+
+    /// Keep build products and remove only profraw, profdata and reports.
+    #[clap(long)]
+    keep_build: bool,
+}
+
+/// What gets executed to collect coverage.
+#[derive(Debug)]
+enum Workload<'a> {
+    Tests(&'a TargetOpts, &'a HarnessOpts),
+    Run(&'a RunOpts),
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = SubCommand::parse();
+    let SubCommand::Llvmcov(opts) = opts;
+
+    match &opts.action {
+        Some(Action::Demangle) => {
+            let stdin = io::stdin();
+            let stdout = io::stdout();
+            demangle(stdin.lock(), stdout.lock())?;
+            Ok(())
+        }
+        Some(Action::Report(opts)) => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            let tools = Tools::locate(&opts.tools)?;
+            let metadata = metadata(&tools.cargo).context("failed to get cargo metadata.")?;
+            let target = target_dir(&metadata);
+            let manifest = report_manifest(&tools, &metadata, &target)?;
+            write_report(
+                &opts.report,
+                &tools,
+                &target.join("default.profdata"),
+                &manifest,
+                &metadata,
+                &target,
+            )
+        }
+        Some(Action::ShowEnv(opts)) => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            let tools = Tools::locate(&opts.tools)?;
+            let metadata = metadata(&tools.cargo).context("failed to get cargo metadata.")?;
+            let target = target_dir(&metadata);
+            let config = CargoConfig::load(&env::current_dir()?)?;
+            let triple = match &opts.target {
+                Some(triple) => triple.clone(),
+                None => tools.rustc_version.host.clone(),
+            };
+            let cfgs = target_cfgs(&rustc(), &triple).context("failed to get target cfg.")?;
+            let flags = Flags::resolve(&config, &triple, &cfgs, |key| env::var(key).ok())?
+                .instrumented(tools.rustc_version.instrumentation()?);
+
+            let env = show_env(&flags, &target, opts.tools.toolchain.as_deref());
+            print!("{}", env_script(&env));
+            Ok(())
+        }
+        Some(Action::Merge(opts)) => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            let tools = Tools::locate(&opts.tools)?;
+            let metadata = metadata(&tools.cargo).context("failed to get cargo metadata.")?;
+            let target = target_dir(&metadata);
+            fs::create_dir_all(&target)
+                .with_context(|| format!("failed to create {}", target.to_string_lossy()))?;
+            let manifest = merge(&tools.llvm_profdata, &opts.inputs, &target)?;
+            write_report(
+                &opts.report,
+                &tools,
+                &target.join("default.profdata"),
+                &manifest,
+                &metadata,
+                &target,
+            )
+        }
+        Some(Action::Doctor(opts)) => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            let checks = doctor(&opts.tools);
+            print!("{}", doctor_table(&checks));
+            let failed = checks.iter().filter(|check| check.result.is_err()).count();
+            if failed > 0 {
+                anyhow::bail!("{} of {} checks failed.", failed, checks.len());
+            }
+            Ok(())
+        }
+        Some(Action::Clean(opts)) => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            let metadata = metadata(&cargo()).context("failed to get cargo metadata.")?;
+            clean(&target_dir(&metadata), opts.keep_build)
+        }
+        Some(Action::Run(run)) => {
+            stderrlog::new().verbosity(run.verbose).init()?;
+            coverage(&run.report, run.keep, &run.build, Workload::Run(run))
+        }
+        None => {
+            stderrlog::new().verbosity(opts.verbose).init()?;
+            coverage(
+                &opts.report,
+                opts.keep,
+                &opts.build,
+                Workload::Tests(&opts.targets, &opts.harness),
+            )
+        }
+    }
+}
+
+/// Binaries of the selected toolchain.
+#[derive(Debug)]
+struct Tools {
+    cargo: PathBuf,
+    rustc_version: RustcVersion,
+    llvm_profdata: PathBuf,
+    llvm_cov: PathBuf,
+    demangler: Vec<String>,
+}
+
+impl Tools {
+    fn locate(opts: &ToolchainOpts) -> anyhow::Result<Self> {
+        let (cargo, rustc) = Self::binaries(opts)?;
+
+        let rustc_version = rustc_version(&rustc).context("failed to get rustc version.")?;
+        log::debug!("rustc: {:?}", rustc_version);
+
+        let llvm_dir = Self::llvm_dir(opts, &rustc, &rustc_version)?;
+        let (llvm_profdata, _) =
+            Self::checked_llvm_tool(opts, &llvm_dir, &rustc_version, "llvm-profdata")?;
+        let (llvm_cov, _) = Self::checked_llvm_tool(opts, &llvm_dir, &rustc_version, "llvm-cov")?;
+
+        Ok(Self {
+            cargo,
+            rustc_version,
+            llvm_profdata,
+            llvm_cov,
+            demangler: demangler()?,
+        })
+    }
+
+    /// cargo and rustc, of the rustup toolchain if one is given.
+    fn binaries(opts: &ToolchainOpts) -> anyhow::Result<(PathBuf, PathBuf)> {
+        match &opts.toolchain {
+            Some(toolchain) => {
+                // Anything spawned through a rustup proxy (e.g. rustdoc) follows this too.
+                env::set_var("RUSTUP_TOOLCHAIN", toolchain);
+                let rustc = toolchain_binary(toolchain, "rustc")?;
+                env::set_var("RUSTC", &rustc);
+                Ok((toolchain_binary(toolchain, "cargo")?, rustc))
+            }
+            None => Ok((cargo(), rustc())),
+        }
+    }
+
+    fn llvm_dir(
+        opts: &ToolchainOpts,
+        rustc: &Path,
+        rustc_version: &RustcVersion,
+    ) -> anyhow::Result<PathBuf> {
+        match &opts.llvm_path {
+            Some(dir) => Ok(dir.clone()),
+            None => {
+                llvm_tools_dir(rustc, &rustc_version.host).context("failed to get llvm-tools path.")
+            }
+        }
+    }
+
+    /// Path of an LLVM tool that exists and matches rustc's LLVM, and its
+    /// version when rustc reports one to compare with.
+    fn checked_llvm_tool(
+        opts: &ToolchainOpts,
+        llvm_dir: &Path,
+        rustc_version: &RustcVersion,
+        name: &str,
+    ) -> anyhow::Result<(PathBuf, Option<String>)> {
+        let tool = llvm_tool(llvm_dir, name);
+        if !tool.exists() {
+            match &opts.llvm_path {
+                Some(_) => anyhow::bail!("No {} exists.", tool.to_string_lossy()),
+                None => anyhow::bail!(
+                    "No {} exists.May be needs `rustup component add llvm-tools{{-preview}}?`",
+                    name
+                ),
+            }
+        }
+        let version = match &rustc_version.llvm {
+            Some(expected) => check_llvm_version(&tool, expected)?,
+            None => None,
+        };
+        Ok((tool, version))
+    }
+}
+
+/// One row of the `doctor` table.
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    result: anyhow::Result<String>,
+    fix: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: anyhow::Result<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            result,
+            fix: fix.into(),
+        }
+    }
+}
+
+/// Runs the same steps as `Tools::locate`, but keeps going after a failure
+/// where later checks don't depend on it.
+fn doctor(opts: &ToolchainOpts) -> Vec<Check> {
+    let mut checks = vec![];
+
+    let (cargo, rustc) = match Tools::binaries(opts) {
+        Ok(binaries) => binaries,
+        Err(e) => {
+            checks.push(Check::new(
+                "toolchain",
+                Err(e),
+                "Install it with `rustup toolchain install <name>`.",
+            ));
+            return checks;
+        }
+    };
+
+    let rustc_version = rustc_version(&rustc);
+    checks.push(Check::new(
+        "rustc",
+        match &rustc_version {
+            Ok(version) => Ok(format!(
+                "{} {} (LLVM {})",
+                rustc.to_string_lossy(),
+                version.release,
+                version.llvm.as_deref().unwrap_or("unknown")
+            )),
+            Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+        },
+        "Install Rust with rustup.",
+    ));
+    checks.push(Check::new(
+        "cargo metadata",
+        metadata(&cargo).map(|metadata| {
+            format!(
+                "{} (target directory {})",
+                cargo.to_string_lossy(),
+                target_dir(&metadata).to_string_lossy()
+            )
+        }),
+        "Run in a directory of a cargo project.",
+    ));
+    checks.push(Check::new(
+        "demangler",
+        demangler().map(|demangler| demangler.join(" ")),
+        "",
+    ));
+
+    let rustc_version = match rustc_version {
+        Ok(version) => version,
+        Err(_) => return checks,
+    };
+    let instrumentation = rustc_version.instrumentation();
+    checks.push(Check::new(
+        "instrumentation",
+        match &instrumentation {
+            Ok(instrumentation) => Ok(instrumentation.flag().to_owned()),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        },
+        "Update with `rustup update`.",
+    ));
+
+    let llvm_dir = match Tools::llvm_dir(opts, &rustc, &rustc_version) {
+        Ok(dir) => dir,
+        Err(e) => {
+            checks.push(Check::new(
+                "llvm-tools",
+                Err(e),
+                "Run `rustup component add llvm-tools-preview`.",
+            ));
+            return checks;
+        }
+    };
+    let fix = match rustc_version.llvm.as_deref().and_then(llvm_major) {
+        Some(major) => format!(
+            "Run `rustup component add llvm-tools-preview`, or use `--llvm-path` with LLVM {} binaries.",
+            major
+        ),
+        None => "Run `rustup component add llvm-tools-preview`.".to_owned(),
+    };
+    let mut llvm_profdata = None;
+    for name in &["llvm-profdata", "llvm-cov"] {
+        let tool = Tools::checked_llvm_tool(opts, &llvm_dir, &rustc_version, name);
+        if let Ok((tool, _)) = &tool {
+            if *name == "llvm-profdata" {
+                llvm_profdata = Some(tool.clone());
+            }
+        }
+        checks.push(Check::new(
+            name,
+            tool.map(|(tool, version)| match version {
+                Some(version) => format!("{} (LLVM {})", tool.to_string_lossy(), version),
+                None => tool.to_string_lossy().into_owned(),
+            }),
+            fix.clone(),
+        ));
+    }
+
+    if let (Ok(instrumentation), Some(llvm_profdata)) = (instrumentation, llvm_profdata) {
+        checks.push(Check::new(
+            "profraw",
+            smoke_test(&rustc, instrumentation, &llvm_profdata),
+            "Use a toolchain that ships the profiler runtime, e.g. one installed by rustup.",
+        ));
+    }
+    checks
+}
+
+/// Builds and runs a tiny instrumented program, then reads its profile back.
+fn smoke_test(
+    rustc: &Path,
+    instrumentation: Instrumentation,
+    llvm_profdata: &Path,
+) -> anyhow::Result<String> {
+    let dir = env::temp_dir().join(format!("cargo-llvmcov-doctor-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let result = smoke_test_in(&dir, rustc, instrumentation, llvm_profdata);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        log::warn!("failed to remove dir {}", e);
+    }
+    result
+}
+
+fn smoke_test_in(
+    dir: &Path,
+    rustc: &Path,
+    instrumentation: Instrumentation,
+    llvm_profdata: &Path,
+) -> anyhow::Result<String> {
+    let source = dir.join("main.rs");
+    fs::write(&source, "fn main() {}\n")?;
+    let exe = dir.join(format!("main{}", env::consts::EXE_SUFFIX));
+
+    let mut command = Command::new(rustc);
+    if instrumentation == Instrumentation::Unstable {
+        command.env("RUSTC_BOOTSTRAP", "1");
+    }
+    let result = command
+        .arg(instrumentation.flag())
+        .arg("-o")
+        .arg(&exe)
+        .arg(&source)
+        .status()?;
+    if !result.success() {
+        anyhow::bail!("failed to compile an instrumented program.");
+    }
+
+    let profenv = Profenv::new(dir)?;
+    execute(&exe, &[], &[], &profenv)?;
+    if profenv.profraw_files()?.is_empty() {
+        anyhow::bail!("the instrumented program wrote no profraw.");
+    }
+    merge_profdata(llvm_profdata, &profenv).context("failed to read the profraw.")?;
+    Ok("written and read by llvm-profdata".to_owned())
+}
+
+fn doctor_table(checks: &[Check]) -> String {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut table = String::new();
+    for check in checks {
+        match &check.result {
+            Ok(detail) => {
+                table.push_str(&format!(
+                    "PASS  {:width$}  {}\n",
+                    check.name,
+                    detail,
+                    width = width
+                ));
+            }
+            Err(e) => {
+                table.push_str(&format!(
+                    "FAIL  {:width$}  {:#}\n",
+                    check.name,
+                    e,
+                    width = width
+                ));
+                if !check.fix.is_empty() {
+                    table.push_str(&format!(
+                        "      {:width$}  fix: {}\n",
+                        "",
+                        check.fix,
+                        width = width
+                    ));
+                }
+            }
+        }
+    }
+    table
+}
+
+fn coverage(
+    report: &ReportOpts,
+    keep: bool,
+    build_opts: &BuildOpts,
+    workload: Workload,
+) -> anyhow::Result<()> {
+    let tools = Tools::locate(&build_opts.tools)?;
+    let instrumentation = tools.rustc_version.instrumentation()?;
+    let cargo = &tools.cargo;
+
+    let metadata = metadata(cargo).context("failed to get cargo metadata.")?;
+    let selected = selected_packages(&metadata, build_opts)?;
+    let target = target_dir(&metadata);
+    fs::create_dir_all(&target)
+        .with_context(|| format!("failed to create {}", target.to_string_lossy()))?;
+    let profenv = Profenv::new(&target)?;
+    let config = CargoConfig::load(&env::current_dir()?)?;
+    let triple = match &build_opts.target {
+        Some(triple) => triple.clone(),
+        None => tools.rustc_version.host.clone(),
+    };
+    let cfgs = target_cfgs(&rustc(), &triple).context("failed to get target cfg.")?;
+    let runner = target_runner(&config, &triple, &cfgs)?;
+    let flags = Flags::resolve(&config, &triple, &cfgs, |key| env::var(key).ok())?
+        .instrumented(instrumentation);
+    // `-v` enables warnings, the first level above the default.
+    let verbose = log::log_enabled!(log::Level::Warn);
+    if verbose {
+        eprintln!("RUSTFLAGS: {}", flags.rustflags.join(" "));
+    }
+
+    log::debug!("cargo binary: {:?}", cargo);
+    log::debug!("output directory: {:?}", target);
+    log::debug!("llvm-profdata: {:?}", tools.llvm_profdata);
+    log::debug!("LLVM_PROFILE_FILE: {:?}", profenv.profraw());
+    log::debug!("runner: {:?}", runner);
+
+    let artifacts = match workload {
+        Workload::Tests(targets, harness) => {
+            let mut artifacts = build(cargo, &target, &profenv, build_opts, targets, &flags)
+                .context("failed to build executables.")?;
+            log::debug!("executables: {:?}", artifacts.executables);
+
+            run_tests(&artifacts.executables, harness, &runner, &profenv)?;
+
+            if targets.doctests {
+                if verbose {
+                    eprintln!("RUSTDOCFLAGS: {}", flags.rustdocflags.join(" "));
+                }
+                let doctests = run_doctests(cargo, &target, &profenv, build_opts, harness, &flags)
+                    .context("failed to run doctests.")?;
+                log::debug!("doctests: {:?}", doctests);
+                artifacts.executables.extend(doctests);
+            }
+            artifacts
+        }
+        Workload::Run(run) => {
+            let artifacts = build_bin(
+                cargo,
+                &target,
+                &profenv,
+                build_opts,
+                run.bin.as_deref(),
+                &flags,
+            )
+            .context("failed to build executables.")?;
+            log::debug!("executables: {:?}", artifacts.executables);
+
+            execute(&artifacts.executables[0], &run.args, &runner, &profenv)?;
+            artifacts
+        }
+    };
+    merge_profdata(&tools.llvm_profdata, &profenv)?;
+
+    let ignore = ignore_regex(&[cargo_home(), rustup_home()], &metadata, &selected);
+    let title = report_title(&selected, &artifacts);
+    log::debug!("ignore: {:?}", ignore);
+    log::debug!("title: {:?}", title);
+    let manifest = Manifest {
+        executables: artifacts.executables,
+        title,
+        ignore,
+    };
+
+    write_report(
+        report,
+        &tools,
+        &profenv.profdata,
+        &manifest,
+        &metadata,
+        &target,
+    )?;
+
+    if keep {
+        manifest.save(&profenv.manifest)?;
+        mem::forget(profenv);
+    }
+
+    Ok(())
+}
+
+fn crates(metadata: &Metadata) -> Vec<formats::Crate<'_>> {
+    metadata
+        .packages
+        .iter()
+        .filter_map(|p| {
+            Some(formats::Crate {
+                name: &p.name,
+                dir: p.manifest_path.parent()?,
+            })
+        })
+        .collect()
+}
+
+fn coverage_data(
+    tools: &Tools,
+    profdata: &Path,
+    manifest: &Manifest,
+) -> anyhow::Result<export::Coverage> {
+    let export = llvm_cov_export_text(
+        &tools.llvm_cov,
+        &tools.demangler,
+        profdata,
+        &manifest.executables,
+        &manifest.ignore,
+    )?;
+    export::Coverage::new(export, &manifest.title)
+}
+
+fn create_file(path: &Path) -> anyhow::Result<fs::File> {
+    fs::File::create(path).with_context(|| format!("failed to create {}", path.to_string_lossy()))
+}
+
+fn write_report(
+    report: &ReportOpts,
+    tools: &Tools,
+    profdata: &Path,
+    manifest: &Manifest,
+    metadata: &Metadata,
+    target: &Path,
+) -> anyhow::Result<()> {
+    let Manifest {
+        executables,
+        title,
+        ignore,
+    } = manifest;
+
+    log::debug!("generating report..");
+    match report {
+        ReportOpts { lcov: true, .. } => {
+            llvm_cov_export(
+                &tools.llvm_cov,
+                &tools.demangler,
+                profdata,
+                executables,
                 &target.join("cov.info"),
-                &cargo_home,
+                ignore,
+                title,
             )?;
         }
-        Opts {
+        ReportOpts {
             lcov_output: Some(lcov),
             ..
         } => {
-            llvm_cov_export(&llvm_cov, &rustfilt, &profenv, &executables, &lcov, &ignore)?;
+            llvm_cov_export(
+                &tools.llvm_cov,
+                &tools.demangler,
+                profdata,
+                executables,
+                lcov,
+                ignore,
+                title,
+            )?;
+        }
+        ReportOpts {
+            json: Some(json), ..
+        } => {
+            let coverage = coverage_data(tools, profdata, manifest)?;
+            serde_json::to_writer_pretty(create_file(json)?, &coverage)?;
+        }
+        ReportOpts {
+            cobertura: Some(cobertura),
+            ..
+        } => {
+            let coverage = coverage_data(tools, profdata, manifest)?;
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+            let xml = formats::cobertura(
+                &coverage,
+                &metadata.workspace_root,
+                &crates(metadata),
+                timestamp,
+            );
+            create_file(cobertura)?.write_all(xml.as_bytes())?;
+        }
+        ReportOpts {
+            sonar: Some(sonar), ..
+        } => {
+            let coverage = coverage_data(tools, profdata, manifest)?;
+            let xml = formats::sonar(&coverage, &metadata.workspace_root);
+            create_file(sonar)?.write_all(xml.as_bytes())?;
+        }
+        ReportOpts {
+            codecov: Some(codecov),
+            ..
+        } => {
+            let coverage = coverage_data(tools, profdata, manifest)?;
+            let codecov_json = formats::codecov(&coverage, &metadata.workspace_root);
+            serde_json::to_writer_pretty(create_file(codecov)?, &codecov_json)?;
+        }
+        ReportOpts {
+            coveralls: Some(coveralls),
+            ..
+        } => {
+            let coverage = coverage_data(tools, profdata, manifest)?;
+            let coveralls_json =
+                formats::coveralls(&coverage, &metadata.workspace_root, |path| fs::read(path))?;
+            serde_json::to_writer_pretty(create_file(coveralls)?, &coveralls_json)?;
         }
-        Opts { html: true, .. } => {
+        ReportOpts { html: true, .. } => {
             llvm_cov_show(
-                &llvm_cov,
-                &rustfilt,
-                &profenv,
-                &executables,
+                &tools.llvm_cov,
+                &tools.demangler,
+                profdata,
+                executables,
                 Some(&target.join("html")),
-                &ignore,
+                ignore,
+                title,
             )?;
         }
+        ReportOpts { summary: true, .. } | ReportOpts { text: false, .. } => {
+            llvm_cov_report(&tools.llvm_cov, profdata, executables, ignore, title)?;
+        }
         _ => {
-            llvm_cov_show(&llvm_cov, &rustfilt, &profenv, &executables, None, &ignore)?;
+            llvm_cov_show(
+                &tools.llvm_cov,
+                &tools.demangler,
+                profdata,
+                executables,
+                None,
+                ignore,
+                title,
+            )?;
         }
     }
 
-    if opts.keep {
-        mem::forget(profenv);
-    }
-
-    if opts.open {
+    if report.open {
         opener::open(&target.join("html/index.html"))?;
     }
 
@@ -5,15 +5,31 @@ use capture_logger::{begin_capture, pop_captured};
 fn test_build_message() {
     let m = r#"{"reason":"compiler-message","package_id":"cargo-llvmcov 0.1.0 (path+file:///home/ysk/work/cargo-llvmcov)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-llvmcov","src_path":"/home/ysk/work/cargo-llvmcov/src/main.rs","edition":"2018","doctest":false,"test":true},"message":{"rendered":"warning: unused variable: `a`\n  --> src/main.rs:50:13\n   |\n50 |         let a = 1;\n   |             ^ help: if this is intentional, prefix it with an underscore: `_a`\n   |\n   = note: `#[warn(unused_variables)]` on by default\n\n","children":[{"children":[],"code":null,"level":"note","message":"`#[warn(unused_variables)]` on by default","rendered":null,"spans":[]},{"children":[],"code":null,"level":"help","message":"if this is intentional, prefix it with an underscore","rendered":null,"spans":[{"byte_end":1044,"byte_start":1043,"column_end":14,"column_start":13,"expansion":null,"file_name":"src/main.rs","is_primary":true,"label":null,"line_end":50,"line_start":50,"suggested_replacement":"_a","suggestion_applicability":"MachineApplicable","text":[{"highlight_end":14,"highlight_start":13,"text":"        let a = 1;"}]}]}],"code":{"code":"unused_variables","explanation":null},"level":"warning","message":"unused variable: `a`","spans":[{"byte_end":1044,"byte_start":1043,"column_end":14,"column_start":13,"expansion":null,"file_name":"src/main.rs","is_primary":true,"label":null,"line_end":50,"line_start":50,"suggested_replacement":null,"suggestion_applicability":null,"text":[{"highlight_end":14,"highlight_start":13,"text":"        let a = 1;"}]}]}}"#;
     let m = serde_json::from_str(m).unwrap();
-    assert_eq!(BuildMessage::CompilerMessage {}, m);
+    assert_eq!(
+        BuildMessage::CompilerMessage {
+            message: Diagnostic {
+                level: "warning".into(),
+                rendered: Some("warning: unused variable: `a`\n  --> src/main.rs:50:13\n   |\n50 |         let a = 1;\n   |             ^ help: if this is intentional, prefix it with an underscore: `_a`\n   |\n   = note: `#[warn(unused_variables)]` on by default\n\n".into()),
+            }
+        },
+        m
+    );
 
     let m = r#"{"reason":"compiler-artifact","package_id":"cargo-llvmcov 0.1.0 (path+file:///home/ysk/work/cargo-llvmcov)","target":{"kind":["bin"],"crate_types":["bin"],"name":"cargo-llvmcov","src_path":"/home/ysk/work/cargo-llvmcov/src/main.rs","edition":"2018","doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/home/ysk/work/cargo-llvmcov/target/debug/cargo-llvmcov"],"executable":"/home/ysk/work/cargo-llvmcov/target/debug/cargo-llvmcov","fresh":true}"#;
     let m = serde_json::from_str(m).unwrap();
     assert_eq!(
         BuildMessage::CompilerArtifact {
-            target: BuildTarget { test: true },
+            package_id: "cargo-llvmcov 0.1.0 (path+file:///home/ysk/work/cargo-llvmcov)".into(),
+            features: vec![],
+            target: BuildTarget {
+                name: "cargo-llvmcov".into(),
+                kind: vec!["bin".into()],
+                test: true
+            },
             profile: BuildProfile { test: false },
-            executable: Some("/home/ysk/work/cargo-llvmcov/target/debug/cargo-llvmcov".into())
+            filenames: vec!["/home/ysk/work/cargo-llvmcov/target/debug/cargo-llvmcov".into()],
+            executable: Some("/home/ysk/work/cargo-llvmcov/target/debug/cargo-llvmcov".into()),
+            fresh: true,
         },
         m
     );
@@ -25,6 +41,30 @@ fn test_build_message() {
     let m = r#"{"reason":"build-finished","success":true}"#;
     let m = serde_json::from_str(m).unwrap();
     assert_eq!(BuildMessage::BuildFinished { success: true }, m);
+
+    let m = r#"{"reason":"timing-info","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"mode":"build","duration":0.1,"rmeta_time":0.05}"#;
+    let m = serde_json::from_str(m).unwrap();
+    assert_eq!(BuildMessage::Other, m);
+
+    let m = r#"{"reason":"some-future-message","payload":[1,2,3]}"#;
+    let m = serde_json::from_str(m).unwrap();
+    assert_eq!(BuildMessage::Other, m);
+
+    // older cargo does not report `fresh`.
+    let m = r#"{"reason":"compiler-artifact","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/tmp/x/target/debug/deps/libx-1.rlib"],"executable":null}"#;
+    let m = serde_json::from_str::<BuildMessage>(m).unwrap();
+    match m {
+        BuildMessage::CompilerArtifact {
+            filenames, fresh, ..
+        } => {
+            assert_eq!(
+                filenames,
+                &[PathBuf::from("/tmp/x/target/debug/deps/libx-1.rlib")]
+            );
+            assert!(!fresh);
+        }
+        m => panic!("{:?}", m),
+    }
 }
 
 #[test]
@@ -94,11 +134,236 @@ fn test_rustup_home() {
 }
 
 #[test]
-fn test_target_dir() {
+fn test_metadata() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#"{"packages":[{"name":"x","version":"0.1.0","id":"x 0.1.0 (path+file:///tmp/x)","license":null,"license_file":null,"description":null,"source":null,"dependencies":[],"targets":[{"kind":["bin"],"crate_types":["bin"],"name":"x","src_path":"/tmp/x/src/main.rs","edition":"2018","doctest":false,"test":true}],"features":{},"manifest_path":"/tmp/x/Cargo.toml","metadata":null,"publish":null,"authors":["yskszk63 <yskszk63@gmail.com>"],"categories":[],"keywords":[],"readme":null,"repository":null,"edition":"2018","links":null}],"workspace_members":["x 0.1.0 (path+file:///tmp/x)"],"resolve":{"nodes":[{"id":"x 0.1.0 (path+file:///tmp/x)","dependencies":[],"deps":[],"features":[]}],"root":"x 0.1.0 (path+file:///tmp/x)"},"target_directory":"/tmp/x/target","version":1,"workspace_root":"/tmp/x","metadata":null}"#, true)));
 
-    let target_dir = target_dir(&cargo()).unwrap();
-    assert_eq!(PathBuf::from("/tmp/x/target"), target_dir);
+    let metadata = metadata(&cargo()).unwrap();
+    assert_eq!(PathBuf::from("/tmp/x/target"), metadata.target_directory);
+}
+
+fn workspace_metadata() -> Metadata {
+    fn package(name: &str, dir: &str) -> String {
+        format!(
+            r#"{{"name":"{name}","version":"0.1.0","id":"{name} 0.1.0 (path+file://{dir})","license":null,"license_file":null,"description":null,"source":null,"dependencies":[],"targets":[{{"kind":["lib"],"crate_types":["lib"],"name":"{name}","src_path":"{dir}/src/lib.rs","edition":"2018","doctest":true,"test":true}},{{"kind":["test"],"crate_types":["bin"],"name":"it","src_path":"{dir}/tests/it.rs","edition":"2018","doctest":false,"test":true}},{{"kind":["custom-build"],"crate_types":["bin"],"name":"build-script-build","src_path":"{dir}/build.rs","edition":"2018","doctest":false,"test":false}}],"features":{{}},"manifest_path":"{dir}/Cargo.toml","metadata":null,"publish":null,"authors":[],"categories":[],"keywords":[],"readme":null,"repository":null,"edition":"2018","links":null}}"#,
+            name = name,
+            dir = dir
+        )
+    }
+
+    let json = format!(
+        r#"{{"packages":[{},{},{}],"workspace_members":["root 0.1.0 (path+file:///ws)","a 0.1.0 (path+file:///ws/crates/a)","b 0.1.0 (path+file:///ws/crates/b)"],"resolve":{{"nodes":[],"root":"root 0.1.0 (path+file:///ws)"}},"target_directory":"/ws/target","version":1,"workspace_root":"/ws","metadata":null}}"#,
+        package("root", "/ws"),
+        package("a", "/ws/crates/a"),
+        package("b", "/ws/crates/b"),
+    );
+    serde_json::from_str(&json).unwrap()
+}
+
+fn names(packages: &[&Package]) -> Vec<String> {
+    packages.iter().map(|p| p.name.clone()).collect()
+}
+
+#[test]
+fn test_selected_packages() {
+    let metadata = workspace_metadata();
+
+    let opts = BuildOpts::default();
+    let r = selected_packages(&metadata, &opts).unwrap();
+    assert_eq!(names(&r), &["root"]);
+
+    let opts = BuildOpts {
+        workspace: true,
+        ..Default::default()
+    };
+    let r = selected_packages(&metadata, &opts).unwrap();
+    assert_eq!(names(&r), &["root", "a", "b"]);
+
+    let opts = BuildOpts {
+        workspace: true,
+        exclude: vec!["root".into()],
+        ..Default::default()
+    };
+    let r = selected_packages(&metadata, &opts).unwrap();
+    assert_eq!(names(&r), &["a", "b"]);
+
+    let opts = BuildOpts {
+        package: vec!["b".into()],
+        ..Default::default()
+    };
+    let r = selected_packages(&metadata, &opts).unwrap();
+    assert_eq!(names(&r), &["b"]);
+
+    let opts = BuildOpts {
+        package: vec!["x".into()],
+        ..Default::default()
+    };
+    let r = selected_packages(&metadata, &opts).unwrap_err();
+    assert_eq!(&r.to_string(), "package `x` not found in workspace.");
+}
+
+#[test]
+fn test_escape_regex() {
+    assert_eq!(escape_regex("/a/b"), "/a/b");
+    assert_eq!(escape_regex("/a.b/c+d"), "/a\\.b/c\\+d");
+    assert_eq!(escape_regex("C:\\x"), "C:\\\\x");
+}
+
+#[test]
+fn test_ignore_regex() {
+    let metadata = workspace_metadata();
+    let root = &metadata.packages[0];
+    let a = &metadata.packages[1];
+
+    assert_eq!(
+        ignore_regex(&[], &metadata, &[root]),
+        "/ws/crates/a/src/|/ws/crates/a/tests/|/ws/crates/b/src/|/ws/crates/b/tests/"
+    );
+    assert_eq!(
+        ignore_regex(&["/home/.cargo".into(), "".into()], &metadata, &[root, a]),
+        "/home/\\.cargo|/ws/crates/b/src/|/ws/crates/b/tests/"
+    );
+    assert_eq!(
+        ignore_regex(&[], &metadata, &[a]),
+        "/ws/src/|/ws/tests/|/ws/crates/b/src/|/ws/crates/b/tests/"
+    );
+}
+
+#[test]
+fn test_build_opts_args() {
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "-p", "a", "--package", "b"]);
+    assert_eq!(opts.build.args(), &["--package", "a", "--package", "b"]);
+
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "--workspace", "--exclude", "a"]);
+    assert_eq!(opts.build.args(), &["--workspace", "--exclude", "a"]);
+
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "--target", "aarch64-unknown-linux-gnu"]);
+    assert_eq!(
+        opts.build.args(),
+        &["--target", "aarch64-unknown-linux-gnu"]
+    );
+
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "--toolchain", "nightly"]);
+    assert_eq!(opts.build.tools.toolchain.as_deref(), Some("nightly"));
+    assert!(opts.build.args().is_empty());
+
+    assert!(SubCommand::try_parse_from(["cargo", "llvmcov", "--exclude", "a"]).is_err());
+
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from([
+        "cargo",
+        "llvmcov",
+        "--features",
+        "a,b",
+        "--features",
+        "c",
+        "--all-features",
+        "--no-default-features",
+    ]);
+    assert_eq!(
+        opts.build.args(),
+        &[
+            "--features",
+            "a,b",
+            "--features",
+            "c",
+            "--all-features",
+            "--no-default-features"
+        ]
+    );
+}
+
+#[test]
+fn test_target_opts_args() {
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from(["cargo", "llvmcov"]);
+    assert_eq!(opts.targets.args(), &["--tests"]);
+
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from([
+        "cargo",
+        "llvmcov",
+        "--lib",
+        "--bins",
+        "--test",
+        "a",
+        "--test",
+        "b",
+        "--examples",
+        "--benches",
+    ]);
+    assert_eq!(
+        opts.targets.args(),
+        &[
+            "--lib",
+            "--bins",
+            "--test",
+            "a",
+            "--test",
+            "b",
+            "--examples",
+            "--benches"
+        ]
+    );
+}
+
+#[test]
+fn test_target_opts_wants() {
+    fn target(name: &str, kind: &str, test: bool) -> BuildTarget {
+        BuildTarget {
+            name: name.into(),
+            kind: vec![kind.into()],
+            test,
+        }
+    }
+
+    let opts = TargetOpts::default();
+    assert!(opts.wants(&target("x", "lib", true)));
+    assert!(opts.wants(&target("it", "test", true)));
+    assert!(!opts.wants(&target("ex", "example", false)));
+
+    let opts = TargetOpts {
+        test: vec!["it".into()],
+        examples: true,
+        ..Default::default()
+    };
+    assert!(!opts.wants(&target("x", "lib", true)));
+    assert!(opts.wants(&target("it", "test", true)));
+    assert!(!opts.wants(&target("other", "test", true)));
+    assert!(opts.wants(&target("ex", "example", false)));
+    assert!(!opts.wants(&target("b", "bench", false)));
+
+    let opts = TargetOpts {
+        lib: true,
+        bins: true,
+        benches: true,
+        ..Default::default()
+    };
+    assert!(opts.wants(&target("x", "proc-macro", true)));
+    assert!(opts.wants(&target("x", "bin", true)));
+    assert!(opts.wants(&target("b", "bench", false)));
+    assert!(!opts.wants(&target("it", "test", true)));
+}
+
+#[test]
+fn test_report_title() {
+    let metadata = workspace_metadata();
+    let root = &metadata.packages[0];
+    let a = &metadata.packages[1];
+
+    let mut artifacts = Artifacts::default();
+    artifacts.features.insert(
+        "root 0.1.0 (path+file:///ws)".into(),
+        vec!["default".into(), "std".into()],
+    );
+    artifacts
+        .features
+        .insert("a 0.1.0 (path+file:///ws/crates/a)".into(), vec![]);
+
+    assert_eq!(
+        report_title(&[root, a], &artifacts),
+        "root (features: default, std); a (no features)"
+    );
 }
 
 #[test]
@@ -114,10 +379,34 @@ fn test_build() {
     let profenv = Profenv::new(&tmpdir).unwrap();
 
     begin_capture();
-    build(&PathBuf::from("cargo"), &PathBuf::from("target"), &profenv).unwrap();
+    let artifacts = build(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        &TargetOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap();
     assert_eq!(
         pop_captured().unwrap().message(),
-        "CALL cargo build --message-format json --tests --target-dir target"
+        "CALL cargo test --no-run --message-format json --tests --target-dir target"
+    );
+    assert_eq!(
+        artifacts.executables,
+        &[
+            PathBuf::from("/home/ysk/work/cargo-llvmcov/target/debug/deps/text-1ed1826ee82efe68"),
+            PathBuf::from(
+                "/home/ysk/work/cargo-llvmcov/target/debug/deps/cargo_llvmcov-24ed17e95a11ece8"
+            ),
+        ]
+    );
+    assert_eq!(
+        artifacts.features.keys().collect::<Vec<_>>(),
+        &[
+            "cargo-binutils 0.3.3 (registry+https://github.com/rust-lang/crates.io-index)",
+            "cargo-llvmcov 0.1.0 (path+file:///home/ysk/work/cargo-llvmcov)",
+        ]
     );
 }
 
@@ -128,10 +417,44 @@ fn test_build_failed() {
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
-    let r = build(&PathBuf::from("cargo"), &PathBuf::from("target"), &profenv).unwrap_err();
+    let r = build(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        &TargetOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap_err();
     assert_eq!(&r.to_string(), "failed to run cargo build.");
 }
 
+#[test]
+fn test_build_failed_with_diagnostics() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#"{"reason":"compiler-message","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"message":{"rendered":"warning: unused variable: `a`\n","children":[],"code":null,"level":"warning","message":"unused variable: `a`","spans":[]}}
+{"reason":"compiler-message","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"message":{"rendered":"error[E0425]: cannot find value `b` in this scope\n --> src/lib.rs:2:5\n","children":[],"code":{"code":"E0425","explanation":null},"level":"error","message":"cannot find value `b` in this scope","spans":[]}}
+{"reason":"compiler-message","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"message":{"rendered":"error: aborting due to previous error\n\n","children":[],"code":null,"level":"error","message":"aborting due to previous error","spans":[]}}
+{"reason":"build-finished","success":false}
+"#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = build(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        &TargetOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        &r.to_string(),
+        "failed to run cargo build.\n\nerror[E0425]: cannot find value `b` in this scope\n --> src/lib.rs:2:5\nerror: aborting due to previous error"
+    );
+}
+
 #[test]
 fn test_build_no_executable_found() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#"{"reason":"compiler-artifact","package_id":"cargo-binutils 0.3.3 (registry+https://github.com/rust-lang/crates.io-index)","target":{"kind":["lib"],"crate_types":["lib"],"name":"cargo-binutils","src_path":"/home/ysk/.cargo/registry/src/github.com-1ecc6299db9ec823/cargo-binutils-0.3.3/src/lib.rs","edition":"2018","doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/home/ysk/work/cargo-llvmcov/target/debug/deps/libcargo_binutils-2869e11bf8c84ac2.rlib","/home/ysk/work/cargo-llvmcov/target/debug/deps/libcargo_binutils-2869e11bf8c84ac2.rmeta"],"executable":null,"fresh":true}
@@ -141,184 +464,1719 @@ fn test_build_no_executable_found() {
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
-    let r = build(&PathBuf::from("cargo"), &PathBuf::from("target"), &profenv).unwrap_err();
+    let r = build(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        &TargetOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap_err();
     assert_eq!(&r.to_string(), "no executable found.");
 }
 
+const BIN_ARTIFACTS: &[u8] = br#"{"reason":"compiler-artifact","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["lib"],"crate_types":["lib"],"name":"x","src_path":"/tmp/x/src/lib.rs","edition":"2018","doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["default"],"filenames":["/tmp/x/target/debug/deps/libx-1.rlib"],"executable":null,"fresh":false}
+{"reason":"compiler-artifact","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["bin"],"crate_types":["bin"],"name":"x","src_path":"/tmp/x/src/main.rs","edition":"2018","doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["default"],"filenames":["/tmp/x/target/debug/x"],"executable":"/tmp/x/target/debug/x","fresh":false}
+{"reason":"build-finished","success":true}
+"#;
+
 #[test]
-fn test_run_test() {
-    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+fn test_build_bin() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((BIN_ARTIFACTS, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
     begin_capture();
-    run_test(&PathBuf::from("program"), &profenv).unwrap();
+    let artifacts = build_bin(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        Some("x"),
+        &Flags::default(),
+    )
+    .unwrap();
     assert_eq!(
         pop_captured().unwrap().message(),
-        "CALL program --nocapture"
+        "CALL cargo build --message-format json --bin x --target-dir target"
+    );
+    assert_eq!(
+        artifacts.executables,
+        &[PathBuf::from("/tmp/x/target/debug/x")]
     );
 }
 
 #[test]
-fn test_run_test_failed() {
-    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+fn test_build_bin_ambiguous() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#"{"reason":"compiler-artifact","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["bin"],"crate_types":["bin"],"name":"x","src_path":"/tmp/x/src/main.rs","edition":"2018","doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["default"],"filenames":["/tmp/x/target/debug/x"],"executable":"/tmp/x/target/debug/x","fresh":false}
+{"reason":"compiler-artifact","package_id":"x 0.1.0 (path+file:///tmp/x)","target":{"kind":["bin"],"crate_types":["bin"],"name":"y","src_path":"/tmp/x/src/bin/y.rs","edition":"2018","doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":["default"],"filenames":["/tmp/x/target/debug/y"],"executable":"/tmp/x/target/debug/y","fresh":false}
+{"reason":"build-finished","success":true}
+"#, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
-    let r = run_test(&PathBuf::from("program"), &profenv).unwrap_err();
-    assert_eq!(&r.to_string(), "failed to run executable.");
+    begin_capture();
+    let r = build_bin(
+        &PathBuf::from("cargo"),
+        &PathBuf::from("target"),
+        &profenv,
+        &BuildOpts::default(),
+        None,
+        &Flags::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL cargo build --message-format json --bins --target-dir target"
+    );
+    assert_eq!(
+        &r.to_string(),
+        "could not determine which binary to run. Use the `--bin` option to specify a binary."
+    );
 }
 
 #[test]
-fn test_merge_profdata() {
+fn test_execute() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
     begin_capture();
-    merge_profdata(&PathBuf::from("llvm-profdata"), &profenv).unwrap();
+    execute(
+        &PathBuf::from("program"),
+        &["--input".into(), "a b".into()],
+        &[],
+        &profenv,
+    )
+    .unwrap();
     assert_eq!(
         pop_captured().unwrap().message(),
-        format!(
-            "CALL llvm-profdata merge -sparse -o {}",
-            tmpdir.join("default.profdata").to_string_lossy()
-        ),
+        "CALL program --input a b"
     );
 }
 
 #[test]
-fn test_merge_profdata_failed() {
-    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
-
-    let tmpdir = mktemp::Temp::new_dir().unwrap();
-    let profenv = Profenv::new(&tmpdir).unwrap();
+fn test_run_opts() {
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from([
+        "cargo", "llvmcov", "run", "--bin", "x", "--html", "-p", "a", "--", "--flag", "value",
+    ]);
+    match opts.action {
+        Some(Action::Run(run)) => {
+            assert_eq!(run.bin.as_deref(), Some("x"));
+            assert!(run.report.html);
+            assert_eq!(run.build.package, &["a"]);
+            assert_eq!(run.args, &["--flag", "value"]);
+        }
+        other => panic!("{:?}", other),
+    }
 
-    let r = merge_profdata(&PathBuf::from("llvm-profdata"), &profenv).unwrap_err();
-    assert_eq!(&r.to_string(), "failed to run llvm-profdata.");
+    assert!(SubCommand::try_parse_from(["cargo", "llvmcov", "--html", "run"]).is_err());
 }
 
 #[test]
-fn test_to_obj_args() {
-    let r = to_obj_args(&[]);
-    assert_eq!(r, &[] as &[PathBuf]);
-
-    let v = vec![PathBuf::from("a")];
-    let r = to_obj_args(&v);
-    assert_eq!(r.len(), 1);
-    assert_eq!(r[0], v[0]);
+fn test_keep_opts() {
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from(["cargo", "llvmcov", "--keep"]);
+    assert!(opts.keep);
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from(["cargo", "llvmcov", "run", "-k"]);
+    assert!(matches!(opts.action, Some(Action::Run(run)) if run.keep));
 
-    let v = vec![PathBuf::from("a"), PathBuf::from("b")];
-    let r = to_obj_args(&v);
-    assert_eq!(r.len(), 3);
-    assert_eq!(r[0], v[0]);
-    assert_eq!(r[1].to_str().unwrap(), "-object");
-    assert_eq!(r[2], v[1]);
+    assert!(SubCommand::try_parse_from(["cargo", "llvmcov", "report", "--keep"]).is_err());
+    assert!(SubCommand::try_parse_from(["cargo", "llvmcov", "merge", "--keep", "a"]).is_err());
 }
 
 #[test]
-fn test_llvm_cov_show() {
+fn test_run_test() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
     begin_capture();
-    llvm_cov_show(
-        &PathBuf::from("llvm-cov"),
-        &PathBuf::from("rustfilt"),
+    run_test(
+        &PathBuf::from("program"),
+        &HarnessOpts::default(),
+        &[],
         &profenv,
-        &[PathBuf::from("exe")],
-        None,
-        "ignore",
     )
     .unwrap();
     assert_eq!(
         pop_captured().unwrap().message(),
-        format!("CALL llvm-cov show -Xdemangler=rustfilt exe -instr-profile={} -format=text -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+        "CALL program --nocapture"
+    );
 }
 
 #[test]
-fn test_llvm_cov_show_html() {
+fn test_run_test_harness_args() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from([
+        "cargo",
+        "llvmcov",
+        "--capture",
+        "--",
+        "--ignored",
+        "--test-threads",
+        "1",
+    ]);
+
     begin_capture();
-    llvm_cov_show(
-        &PathBuf::from("llvm-cov"),
-        &PathBuf::from("rustfilt"),
+    run_test(&PathBuf::from("program"), &opts.harness, &[], &profenv).unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL program --ignored --test-threads 1"
+    );
+}
+
+#[test]
+fn test_run_tests_parallel() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let harness = HarnessOpts {
+        capture: true,
+        args: vec!["--version".into()],
+        jobs: 2,
+    };
+    run_tests(
+        &[PathBuf::from("cargo"), PathBuf::from("cargo")],
+        &harness,
+        &[],
         &profenv,
-        &[PathBuf::from("exe")],
-        Some(&PathBuf::from("output")),
-        "ignore",
     )
     .unwrap();
-    assert_eq!(pop_captured().unwrap().message(),
-        format!("CALL llvm-cov show -Xdemangler=rustfilt exe -instr-profile={} -format=html -output-dir=output -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+
+    let harness = HarnessOpts {
+        capture: true,
+        args: vec!["--no-such-option".into()],
+        jobs: 2,
+    };
+    let r = run_tests(&[PathBuf::from("cargo")], &harness, &[], &profenv).unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run executable.");
 }
 
+#[cfg(unix)]
 #[test]
-fn test_llvm_cov_show_failed() {
-    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+fn test_run_tests_parallel_output() {
+    use std::os::unix::fs::PermissionsExt;
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
-    let r = llvm_cov_show(
-        &PathBuf::from("llvm-cov"),
-        &PathBuf::from("rustfilt"),
+    // Writes its lines slowly, so that the runs overlap.
+    let executables = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            let path = tmpdir.join(name);
+            let status = if *name == "b" { 1 } else { 0 };
+            fs::write(
+                &path,
+                format!(
+                    "#!/bin/sh\nfor i in 1 2 3; do echo {name} $i; echo {name} err $i >&2; sleep 0.05; done\nexit {}\n",
+                    status,
+                    name = name
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        })
+        .collect::<Vec<_>>();
+
+    let harness = HarnessOpts {
+        capture: true,
+        args: vec![],
+        jobs: 3,
+    };
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let r = run_tests_to(
+        &executables,
+        &harness,
+        &[],
         &profenv,
-        &[PathBuf::from("exe")],
-        None,
-        "ignore",
+        &mut stdout,
+        &mut stderr,
     )
     .unwrap_err();
-    assert_eq!(&r.to_string(), "failed to run llvm-cov.");
+    assert_eq!(&r.to_string(), "failed to run executable.");
+
+    // Each run is one block, and the failing `b` did not stop the others.
+    for (output, err) in &[(stdout, ""), (stderr, "err ")] {
+        let output = String::from_utf8(output.clone()).unwrap();
+        assert_eq!(output.lines().count(), 9, "{}", output);
+        for name in &["a", "b", "c"] {
+            let block = (1..=3)
+                .map(|i| format!("{} {}{}\n", name, err, i))
+                .collect::<String>();
+            assert!(output.contains(&block), "{}", output);
+        }
+    }
 }
 
 #[test]
-fn test_llvm_cov_export() {
-    let output = mktemp::Temp::new_file().unwrap();
+fn test_parallel() {
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    let items = (0..8).collect::<Vec<_>>();
+
+    let r = parallel(&items, 3, |i| {
+        let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+        max_running.fetch_max(n, Ordering::SeqCst);
+        thread::sleep(std::time::Duration::from_millis(10));
+        running.fetch_sub(1, Ordering::SeqCst);
+        i * 2
+    });
+    assert_eq!(r, &[0, 2, 4, 6, 8, 10, 12, 14]);
+    assert!(max_running.load(Ordering::SeqCst) <= 3);
+
+    let r = parallel(&[] as &[usize], 3, |i| *i);
+    assert!(r.is_empty());
+}
+
+#[test]
+fn test_run_test_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = run_test(
+        &PathBuf::from("program"),
+        &HarnessOpts::default(),
+        &[],
+        &profenv,
+    )
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run executable.");
+}
 
+#[test]
+fn test_run_test_runner() {
     MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
 
     let tmpdir = mktemp::Temp::new_dir().unwrap();
     let profenv = Profenv::new(&tmpdir).unwrap();
 
     begin_capture();
-    llvm_cov_export(
-        &PathBuf::from("llvm-cov"),
-        &PathBuf::from("rustfilt"),
+    run_test(
+        &PathBuf::from("program"),
+        &HarnessOpts::default(),
+        &[
+            "qemu-aarch64".into(),
+            "-L".into(),
+            "/usr/aarch64-linux-gnu".into(),
+        ],
         &profenv,
-        &[PathBuf::from("exe")],
-        &output,
-        "ignore",
     )
     .unwrap();
-    assert_eq!(pop_captured().unwrap().message(),
-        format!("CALL llvm-cov export -Xdemangler=rustfilt exe -instr-profile={} -format=lcov -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL qemu-aarch64 -L /usr/aarch64-linux-gnu program --nocapture"
+    );
 }
 
 #[test]
-fn test_llvm_cov_export_failed() {
-    let output = mktemp::Temp::new_file().unwrap();
+fn test_flags() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    fs::create_dir_all(tmpdir.join(".cargo")).unwrap();
+    fs::write(
+        tmpdir.join(".cargo/config.toml"),
+        "[build]\nrustflags = [\"--cfg\", \"tokio_unstable\"]\nrustdocflags = \"--cfg docsrs\"\n[target.aarch64-unknown-linux-gnu]\nrustflags = \"-C target-cpu=cortex-a72\"\n",
+    )
+    .unwrap();
+    let config = CargoConfig::load(&tmpdir).unwrap();
+    let no_env = |_: &str| None;
 
-    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+    let flags = Flags::resolve(&config, "x86_64-unknown-linux-gnu", &[], no_env).unwrap();
+    assert_eq!(flags.rustflags, &["--cfg", "tokio_unstable"]);
+    assert_eq!(flags.rustdocflags, &["--cfg", "docsrs"]);
+
+    let flags = Flags::resolve(&config, "aarch64-unknown-linux-gnu", &[], no_env).unwrap();
+    assert_eq!(flags.rustflags, &["-C", "target-cpu=cortex-a72"]);
+
+    let flags = Flags::resolve(&config, "x86_64-unknown-linux-gnu", &[], |key| match key {
+        "RUSTFLAGS" => Some("-D warnings".into()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(flags.rustflags, &["-D", "warnings"]);
+
+    let flags = Flags::resolve(&config, "x86_64-unknown-linux-gnu", &[], |key| match key {
+        "CARGO_ENCODED_RUSTFLAGS" => Some("--cfg\x1ffoo=\"a b\"".into()),
+        "RUSTFLAGS" => Some("-D warnings".into()),
+        _ => None,
+    })
+    .unwrap();
+    assert_eq!(flags.rustflags, &["--cfg", "foo=\"a b\""]);
+
+    let stable = flags.clone().instrumented(Instrumentation::Stable);
+    assert_eq!(
+        stable.rustflags,
+        &["--cfg", "foo=\"a b\"", "-Cinstrument-coverage"]
+    );
+    assert!(!stable.bootstrap);
+
+    let flags = flags.instrumented(Instrumentation::Unstable);
+    assert_eq!(
+        flags.rustflags,
+        &["--cfg", "foo=\"a b\"", "-Zinstrument-coverage"]
+    );
+    assert_eq!(
+        flags.rustdocflags,
+        &["--cfg", "docsrs", "-Zinstrument-coverage"]
+    );
+    assert!(flags.bootstrap);
+}
 
+#[test]
+fn test_flags_cfg() {
     let tmpdir = mktemp::Temp::new_dir().unwrap();
-    let profenv = Profenv::new(&tmpdir).unwrap();
+    fs::create_dir_all(tmpdir.join(".cargo")).unwrap();
+    fs::write(
+        tmpdir.join(".cargo/config.toml"),
+        "[build]\nrustflags = \"--cfg build\"\n[target.x86_64-unknown-linux-gnu]\nrustflags = \"--cfg triple\"\n[target.'cfg(all(unix, target_arch = \"x86_64\"))']\nrustflags = [\"-C\", \"target-cpu=native\"]\n[target.'cfg(windows)']\nrustflags = \"--cfg windows\"\n",
+    )
+    .unwrap();
+    let config = CargoConfig::load(&tmpdir).unwrap();
+    let cfgs = vec![
+        ("unix".to_owned(), None),
+        ("target_arch".to_owned(), Some("x86_64".to_owned())),
+    ];
 
-    let r = llvm_cov_export(
-        &PathBuf::from("llvm-cov"),
-        &PathBuf::from("rustfilt"),
-        &profenv,
+    let flags = Flags::resolve(&config, "x86_64-unknown-linux-gnu", &cfgs, |_| None).unwrap();
+    assert_eq!(
+        flags.rustflags,
+        &["--cfg", "triple", "-C", "target-cpu=native"]
+    );
+    let flags = Flags::resolve(&config, "x86_64-unknown-freebsd", &cfgs, |_| None).unwrap();
+    assert_eq!(flags.rustflags, &["-C", "target-cpu=native"]);
+    let flags = Flags::resolve(&config, "aarch64-unknown-linux-gnu", &[], |_| None).unwrap();
+    assert_eq!(flags.rustflags, &["--cfg", "build"]);
+
+    fs::write(
+        tmpdir.join(".cargo/config.toml"),
+        "[target.'cfg(unix']\nrustflags = \"\"\n",
+    )
+    .unwrap();
+    let config = CargoConfig::load(&tmpdir).unwrap();
+    let r = Flags::resolve(&config, "x86_64-unknown-linux-gnu", &cfgs, |_| None).unwrap_err();
+    assert_eq!(r.to_string(), "invalid target.'cfg(unix'.");
+}
+
+#[test]
+fn test_cfg() {
+    let cfgs = vec![
+        ("unix".to_owned(), None),
+        ("target_os".to_owned(), Some("linux".to_owned())),
+    ];
+    let cases = [
+        ("cfg(unix)", true),
+        ("cfg(windows)", false),
+        ("cfg(target_os = \"linux\")", true),
+        ("cfg(target_os=\"macos\")", false),
+        ("cfg(not(windows))", true),
+        ("cfg(all(unix, target_os = \"linux\",))", true),
+        ("cfg(all(unix, windows))", false),
+        ("cfg(any(windows, target_os = \"linux\"))", true),
+        ("cfg(any())", false),
+        ("cfg(all())", true),
+    ];
+    for (key, expected) in cases {
+        assert_eq!(Cfg::parse(key).unwrap().matches(&cfgs), expected, "{}", key);
+    }
+
+    for key in [
+        "cfg(unix",
+        "cfg()",
+        "cfg(unix, windows)",
+        "cfg(not(unix, windows))",
+        "cfg(target_os = linux)",
+        "cfg(target_os = \"linux)",
+        "cfg(unix) x",
+        "unix",
+    ] {
+        assert!(Cfg::parse(key).is_err(), "{}", key);
+    }
+}
+
+#[test]
+fn test_target_cfgs() {
+    MOCK_RESULT.with(|o| {
+        o.borrow_mut()
+            .replace((b"debug_assertions\ntarget_arch=\"x86_64\"\nunix\n", true))
+    });
+
+    begin_capture();
+    let cfgs = target_cfgs(&PathBuf::from("rustc"), "x86_64-unknown-linux-gnu").unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL rustc --print cfg --target x86_64-unknown-linux-gnu"
+    );
+    assert_eq!(
+        cfgs,
+        &[
+            ("debug_assertions".to_owned(), None),
+            ("target_arch".to_owned(), Some("x86_64".to_owned())),
+            ("unix".to_owned(), None),
+        ]
+    );
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((b"", false)));
+    let r = target_cfgs(&PathBuf::from("rustc"), "x86_64-unknown-linux-gnu").unwrap_err();
+    assert_eq!(r.to_string(), "failed to run rustc --print cfg.");
+}
+
+#[test]
+fn test_rustc_version() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((b"rustc 1.48.0 (7eac88abb 2020-11-16)\nbinary: rustc\ncommit-hash: 7eac88abb2e57e752f3302f02be5f3ce3d7adfb4\ncommit-date: 2020-11-16\nhost: x86_64-unknown-linux-gnu\nrelease: 1.48.0\nLLVM version: 11.0\n", true)));
+
+    let version = rustc_version(&PathBuf::from("rustc")).unwrap();
+    assert_eq!(
+        version,
+        RustcVersion {
+            release: Version::parse("1.48.0").unwrap(),
+            host: "x86_64-unknown-linux-gnu".into(),
+            llvm: Some("11.0".into()),
+        }
+    );
+    assert_eq!(
+        version.instrumentation().unwrap(),
+        Instrumentation::Unstable
+    );
+}
+
+#[test]
+fn test_llvm_tool() {
+    MOCK_RESULT.with(|o| {
+        o.borrow_mut()
+            .replace((b"/rustup/toolchains/nightly\n", true))
+    });
+
+    begin_capture();
+    let dir = llvm_tools_dir(&PathBuf::from("rustc"), "x86_64-unknown-linux-gnu").unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL rustc --print sysroot"
+    );
+    assert_eq!(
+        llvm_tool(&dir, "llvm-cov"),
+        PathBuf::from(format!(
+            "/rustup/toolchains/nightly/lib/rustlib/x86_64-unknown-linux-gnu/bin/llvm-cov{}",
+            env::consts::EXE_SUFFIX
+        ))
+    );
+}
+
+#[test]
+fn test_llvm_major() {
+    assert_eq!(llvm_major("11.0"), Some(11));
+    assert_eq!(
+        llvm_major("LLVM version 11.0.1-rust-1.48.0-stable"),
+        Some(11)
+    );
+    assert_eq!(llvm_major("Debian LLVM version 14.0.6"), Some(14));
+    assert_eq!(llvm_major("unknown"), None);
+}
+
+#[test]
+fn test_check_llvm_version() {
+    let output =
+        b"LLVM (http://llvm.org/):\n  LLVM version 11.0.1-rust-1.48.0-stable\n  Optimized build.\n";
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((output, true)));
+    begin_capture();
+    let version = check_llvm_version(&PathBuf::from("llvm-profdata"), "11.0").unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL llvm-profdata show --version"
+    );
+    assert_eq!(version.as_deref(), Some("11.0.1-rust-1.48.0-stable"));
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((output, true)));
+    let r = check_llvm_version(&PathBuf::from("llvm-profdata"), "12.0").unwrap_err();
+    assert_eq!(
+        &r.to_string(),
+        "llvm-profdata is LLVM 11, but rustc uses LLVM 12. Install the matching tools with `rustup component add llvm-tools-preview`, or use `--llvm-path` to point to LLVM 12 binaries."
+    );
+}
+
+#[test]
+fn test_toolchain_binary() {
+    MOCK_RESULT.with(|o| {
+        o.borrow_mut()
+            .replace((b"/rustup/toolchains/nightly/bin/cargo\n", true))
+    });
+
+    begin_capture();
+    let path = toolchain_binary("nightly", "cargo").unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        "CALL rustup which --toolchain nightly cargo"
+    );
+    assert_eq!(path, PathBuf::from("/rustup/toolchains/nightly/bin/cargo"));
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((b"", false)));
+    let r = toolchain_binary("nightly", "cargo").unwrap_err();
+    assert_eq!(
+        &r.to_string(),
+        "failed to find cargo of toolchain nightly. May be needs `rustup toolchain install nightly`."
+    );
+}
+
+#[test]
+fn test_rustc_version_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((b"", false)));
+
+    let r = rustc_version(&PathBuf::from("rustc")).unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run rustc -vV.");
+}
+
+#[test]
+fn test_instrumentation() {
+    let version = |release: &str| {
+        RustcVersion::parse(&format!(
+            "rustc {}\nhost: x86_64-unknown-linux-gnu\nrelease: {}\n",
+            release, release
+        ))
+        .unwrap()
+    };
+
+    assert_eq!(
+        version("1.60.0").instrumentation().unwrap(),
+        Instrumentation::Stable
+    );
+    assert_eq!(
+        version("1.75.0-nightly").instrumentation().unwrap(),
+        Instrumentation::Stable
+    );
+    assert_eq!(
+        version("1.59.0-nightly").instrumentation().unwrap(),
+        Instrumentation::Unstable
+    );
+    assert_eq!(
+        &version("1.46.0").instrumentation().unwrap_err().to_string(),
+        "rustc 1.46.0 is too old. Source-based code coverage needs rustc 1.47 or later."
+    );
+}
+
+#[test]
+fn test_cargo_config() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let cwd = tmpdir.join("ws/crate");
+    fs::create_dir_all(cwd.join(".cargo")).unwrap();
+    fs::create_dir_all(tmpdir.join("ws/.cargo")).unwrap();
+    fs::write(
+        cwd.join(".cargo/config.toml"),
+        "[target.aarch64-unknown-linux-gnu]\nrunner = \"qemu-aarch64 -L /usr/aarch64-linux-gnu\"\n[target.'cfg(target_arch = \"aarch64\")']\nrunner = \"ignored\"\n[target.'cfg(target_os = \"none\")']\nrunner = \"probe-run\"\n[target.'cfg(target_arch = \"arm\")']\nrunner = \"qemu-arm\"\n",
+    )
+    .unwrap();
+    fs::write(
+        tmpdir.join("ws/.cargo/config"),
+        "[target.aarch64-unknown-linux-gnu]\nrunner = \"ignored\"\n[target.riscv64gc-unknown-linux-gnu]\nrunner = [\"qemu-riscv64\", \"-L\", \"/usr/riscv64-linux-gnu\"]\n",
+    )
+    .unwrap();
+
+    let config = CargoConfig::load(&cwd).unwrap();
+    assert_eq!(
+        target_runner(&config, "aarch64-unknown-linux-gnu", &[]).unwrap(),
+        &["qemu-aarch64", "-L", "/usr/aarch64-linux-gnu"]
+    );
+    assert_eq!(
+        target_runner(&config, "riscv64gc-unknown-linux-gnu", &[]).unwrap(),
+        &["qemu-riscv64", "-L", "/usr/riscv64-linux-gnu"]
+    );
+    assert!(target_runner(&config, "x86_64-unknown-none", &[])
+        .unwrap()
+        .is_empty());
+
+    // The triple's runner wins over a matching cfg.
+    let aarch64 = vec![("target_arch".to_owned(), Some("aarch64".to_owned()))];
+    assert_eq!(
+        target_runner(&config, "aarch64-unknown-linux-gnu", &aarch64).unwrap(),
+        &["qemu-aarch64", "-L", "/usr/aarch64-linux-gnu"]
+    );
+    let none = vec![
+        ("target_arch".to_owned(), Some("x86_64".to_owned())),
+        ("target_os".to_owned(), Some("none".to_owned())),
+    ];
+    assert_eq!(
+        target_runner(&config, "x86_64-unknown-none", &none).unwrap(),
+        &["probe-run"]
+    );
+    let arm_none = vec![
+        ("target_arch".to_owned(), Some("arm".to_owned())),
+        ("target_os".to_owned(), Some("none".to_owned())),
+    ];
+    let r = target_runner(&config, "thumbv7m-none-eabi", &arm_none).unwrap_err();
+    assert_eq!(
+        r.to_string(),
+        "several target.'cfg(...)'.runner match thumbv7m-none-eabi: cfg(target_arch = \"arm\"), cfg(target_os = \"none\")"
+    );
+
+    let key = "CARGO_TARGET_THUMBV7EM_NONE_EABIHF_RUNNER";
+    env::set_var(key, "probe-run --chip x");
+    assert_eq!(
+        target_runner(&config, "thumbv7em-none-eabihf", &[]).unwrap(),
+        &["probe-run", "--chip", "x"]
+    );
+    env::remove_var(key);
+}
+
+#[test]
+fn test_run_doctests() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    // stale binaries from a previous run are removed.
+    let stale = tmpdir.join("doctests/src_lib_rs_1_0");
+    fs::create_dir_all(&stale).unwrap();
+    fs::write(stale.join("rust_out"), b"").unwrap();
+
+    begin_capture();
+    let r = run_doctests(
+        &PathBuf::from("cargo"),
+        &tmpdir,
+        &profenv,
+        &BuildOpts::default(),
+        &HarnessOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!(
+            "CALL cargo test --doc --target-dir {} -- --nocapture",
+            tmpdir.to_string_lossy()
+        )
+    );
+    assert!(r.is_empty());
+}
+
+#[test]
+fn test_run_doctests_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = run_doctests(
+        &PathBuf::from("cargo"),
+        &tmpdir,
+        &profenv,
+        &BuildOpts::default(),
+        &HarnessOpts::default(),
+        &Flags::default(),
+    )
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run cargo test --doc.");
+}
+
+#[test]
+fn test_doctest_executables() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    for (dir, file) in &[
+        ("src_lib_rs_1_0", "rust_out"),
+        ("src_lib_rs_8_0", "rust_out.exe"),
+        ("src_lib_rs_8_0", "rust_out.pdb"),
+    ] {
+        fs::create_dir_all(tmpdir.join(dir)).unwrap();
+        fs::write(tmpdir.join(dir).join(file), b"").unwrap();
+    }
+
+    let r = doctest_executables(&tmpdir).unwrap();
+    assert_eq!(
+        r,
+        &[
+            tmpdir.join("src_lib_rs_1_0/rust_out"),
+            tmpdir.join("src_lib_rs_8_0/rust_out.exe"),
+        ]
+    );
+}
+
+#[test]
+fn test_merge_profdata() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    begin_capture();
+    merge_profdata(&PathBuf::from("llvm-profdata"), &profenv).unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!(
+            "CALL llvm-profdata merge -sparse -o {}",
+            tmpdir.join("default.profdata").to_string_lossy()
+        ),
+    );
+}
+
+#[test]
+fn test_merge_profdata_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = merge_profdata(&PathBuf::from("llvm-profdata"), &profenv).unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run llvm-profdata.");
+}
+
+#[test]
+fn test_merge() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    // Kept on a CI machine, then downloaded to another path.
+    let shard = |name: &str, executables: &[&str]| {
+        let kept = tmpdir.join("ci").join(name);
+        fs::create_dir_all(kept.join("profraw-1")).unwrap();
+        fs::write(kept.join("profraw-1/1.profraw"), b"").unwrap();
+        fs::create_dir_all(kept.join("debug/deps")).unwrap();
+        for exe in executables {
+            fs::write(kept.join(exe), b"").unwrap();
+        }
+        Manifest {
+            executables: executables.iter().map(|exe| kept.join(exe)).collect(),
+            title: "title".into(),
+            ignore: "ignore".into(),
+        }
+        .save(&Manifest::path(&kept))
+        .unwrap();
+        let dir = tmpdir.join(name);
+        fs::rename(&kept, &dir).unwrap();
+        dir
+    };
+    let a = shard("a", &["debug/deps/x-0123"]);
+    let b = shard("b", &["debug/deps/x-0123"]);
+    fs::write(b.join("default.profdata"), b"").unwrap();
+    let target = tmpdir.join("cov");
+    fs::create_dir_all(&target).unwrap();
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+    begin_capture();
+    let manifest = merge(
+        &PathBuf::from("llvm-profdata"),
+        &[a.join("profraw-1"), b.clone()],
+        &target,
+    )
+    .unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!(
+            "CALL llvm-profdata merge -sparse {} {} -o {}",
+            a.join("profraw-1/1.profraw").to_string_lossy(),
+            b.join("default.profdata").to_string_lossy(),
+            target.join("default.profdata").to_string_lossy()
+        ),
+    );
+    assert_eq!(manifest.executables, &[a.join("debug/deps/x-0123")]);
+    assert_eq!(Manifest::load(&Manifest::path(&target)).unwrap(), manifest);
+
+    fs::remove_file(b.join("debug/deps/x-0123")).unwrap();
+    let r = merge(
+        &PathBuf::from("llvm-profdata"),
+        &[a.clone(), b.clone()],
+        &target,
+    )
+    .unwrap_err();
+    assert_eq!(
+        r.to_string(),
+        format!(
+            "executable {} of {} not found. Merge inputs need the executables of their run.",
+            b.join("debug/deps/x-0123").to_string_lossy(),
+            b.to_string_lossy()
+        )
+    );
+
+    let c = shard("c", &["debug/deps/x-4567"]);
+    let r = merge(
+        &PathBuf::from("llvm-profdata"),
+        &[a.clone(), c.clone()],
+        &target,
+    )
+    .unwrap_err();
+    assert_eq!(
+        r.to_string(),
+        format!(
+            "executables of {} do not match {}: x-0123, x-4567",
+            c.to_string_lossy(),
+            a.to_string_lossy()
+        )
+    );
+
+    let r = merge(
+        &PathBuf::from("llvm-profdata"),
+        &[target.join("html")],
+        &target,
+    )
+    .unwrap_err();
+    assert_eq!(
+        r.to_string(),
+        format!(
+            "invalid merge input {}.",
+            target.join("html").to_string_lossy()
+        )
+    );
+}
+
+const LLVM_COV_EXPORT: &[u8] = br#"{"data":[{"files":[{"branches":[[3,8,3,13,1,1,0,0,4]],"expansions":[],"filename":"/ws/src/lib.rs","segments":[[1,1,2,true,true,false],[3,8,1,true,true],[5,2,0,false,false,false],[6,1,0,true,true,false],[7,2,0,false,false,false]],"summary":{"branches":{"count":2,"covered":2,"notcovered":0,"percent":100},"functions":{"count":1,"covered":1,"percent":100},"instantiations":{"count":1,"covered":1,"percent":100},"lines":{"count":4,"covered":3,"percent":75},"regions":{"count":3,"covered":2,"notcovered":1,"percent":66.666666666666657}}}],"functions":[{"branches":[],"count":2,"filenames":["/ws/src/lib.rs"],"name":"root::f","regions":[[1,1,5,2,2,0,0,0],[4,9,4,20,0,0,0,0]]}],"totals":{"functions":{"count":1,"covered":1,"percent":100},"instantiations":{"count":1,"covered":1,"percent":100},"lines":{"count":4,"covered":3,"percent":75},"regions":{"count":3,"covered":2,"notcovered":1,"percent":66.666666666666657}}}],"type":"llvm.coverage.json.export","version":"2.0.1"}"#;
+
+#[test]
+fn test_llvm_cov_export_text() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((LLVM_COV_EXPORT, true)));
+
+    begin_capture();
+    let export = llvm_cov_export_text(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &PathBuf::from("default.profdata"),
+        &[PathBuf::from("exe")],
+        "ignore",
+    )
+    .unwrap();
+    assert_eq!(pop_captured().unwrap().message(),
+        "CALL llvm-cov export -Xdemangler=demangler exe -instr-profile=default.profdata -format=text -ignore-filename-regex=ignore -show-instantiations=false");
+
+    let file = &export.data[0].files[0];
+    assert_eq!(
+        file.segments[1],
+        export::Segment {
+            line: 3,
+            column: 8,
+            count: 1,
+            has_count: true,
+            is_region_entry: true,
+            is_gap_region: false,
+        }
+    );
+    assert_eq!(
+        file.branches[0],
+        export::Branch {
+            line_start: 3,
+            column_start: 8,
+            line_end: 3,
+            column_end: 13,
+            true_count: 1,
+            false_count: 1,
+            file_id: 0,
+            expanded_file_id: 0,
+            kind: 4,
+        }
+    );
+    assert_eq!(file.summary.branches.covered, 2);
+    let function = &export.data[0].functions[0];
+    assert_eq!(function.name, "root::f");
+    assert_eq!(function.regions[1].execution_count, 0);
+    assert_eq!(export.data[0].totals.branches, export::Count::default());
+
+    let coverage = export::Coverage::new(export, "root (no features)").unwrap();
+    let json = serde_json::to_value(&coverage).unwrap();
+    assert_eq!(json["version"], 1);
+    assert_eq!(json["title"], "root (no features)");
+    assert_eq!(
+        json["files"][0]["segments"][0],
+        serde_json::json!({
+            "line": 1,
+            "column": 1,
+            "count": 2,
+            "has_count": true,
+            "is_region_entry": true,
+            "is_gap_region": false,
+        })
+    );
+    assert_eq!(
+        json["functions"][0]["regions"][0],
+        serde_json::json!({
+            "line_start": 1,
+            "column_start": 1,
+            "line_end": 5,
+            "column_end": 2,
+            "execution_count": 2,
+            "file_id": 0,
+            "expanded_file_id": 0,
+            "kind": 0,
+        })
+    );
+    assert_eq!(
+        json["totals"]["lines"],
+        serde_json::json!({"count": 4, "covered": 3, "percent": 75.0})
+    );
+}
+
+fn fixture_coverage() -> export::Coverage {
+    let export = serde_json::from_slice(LLVM_COV_EXPORT).unwrap();
+    let mut coverage = export::Coverage::new(export, "root (no features)").unwrap();
+    let mut file = coverage.files[0].clone();
+    file.filename = "/ws/crates/a/src/lib.rs".into();
+    file.branches.clear();
+    coverage.files.push(file);
+    coverage
+}
+
+#[test]
+fn test_line_counts() {
+    let coverage = fixture_coverage();
+    let counts = coverage.files[0].line_counts();
+    assert_eq!(
+        counts.into_iter().collect::<Vec<_>>(),
+        &[(1, 2), (2, 2), (3, 2), (4, 1), (5, 1), (6, 0), (7, 0)]
+    );
+    assert_eq!(
+        coverage.files[0]
+            .branch_conditions()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        &[(3, (2, 2))]
+    );
+
+    let skipped: export::File = serde_json::from_str(
+        r#"{"filename":"a","segments":[[1,1,1,true,true,false],[2,1,0,false,true,false],[3,1,0,false,false,false]],"summary":{"lines":{"count":0,"covered":0,"percent":0},"functions":{"count":0,"covered":0,"percent":0},"instantiations":{"count":0,"covered":0,"percent":0},"regions":{"count":0,"covered":0,"percent":0}}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        skipped.line_counts().into_iter().collect::<Vec<_>>(),
+        &[(1, 1)]
+    );
+}
+
+#[test]
+fn test_cobertura() {
+    let metadata = workspace_metadata();
+    let xml = formats::cobertura(
+        &fixture_coverage(),
+        &metadata.workspace_root,
+        &crates(&metadata),
+        1600000000000,
+    );
+    assert_eq!(
+        xml,
+        format!(
+            "<?xml version=\"1.0\" ?>\n\
+             <!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n\
+             <coverage line-rate=\"0.7142857142857143\" branch-rate=\"1\" lines-covered=\"10\" lines-valid=\"14\" branches-covered=\"2\" branches-valid=\"2\" complexity=\"0\" version=\"{}\" timestamp=\"1600000000000\">\n\
+             \x20 <sources>\n\
+             \x20   <source>/ws</source>\n\
+             \x20 </sources>\n\
+             \x20 <packages>\n\
+             \x20   <package name=\"root\" line-rate=\"0.7142857142857143\" branch-rate=\"1\" complexity=\"0\">\n\
+             \x20     <classes>\n\
+             \x20       <class name=\"src/lib.rs\" filename=\"src/lib.rs\" line-rate=\"0.7142857142857143\" branch-rate=\"1\" complexity=\"0\">\n\
+             \x20         <methods/>\n\
+             \x20         <lines>\n\
+             \x20           <line number=\"1\" hits=\"2\" branch=\"false\"/>\n\
+             \x20           <line number=\"2\" hits=\"2\" branch=\"false\"/>\n\
+             \x20           <line number=\"3\" hits=\"2\" branch=\"true\" condition-coverage=\"100% (2/2)\"/>\n\
+             \x20           <line number=\"4\" hits=\"1\" branch=\"false\"/>\n\
+             \x20           <line number=\"5\" hits=\"1\" branch=\"false\"/>\n\
+             \x20           <line number=\"6\" hits=\"0\" branch=\"false\"/>\n\
+             \x20           <line number=\"7\" hits=\"0\" branch=\"false\"/>\n\
+             \x20         </lines>\n\
+             \x20       </class>\n\
+             \x20     </classes>\n\
+             \x20   </package>\n\
+             \x20   <package name=\"a\" line-rate=\"0.7142857142857143\" branch-rate=\"0\" complexity=\"0\">\n\
+             \x20     <classes>\n\
+             \x20       <class name=\"crates/a/src/lib.rs\" filename=\"crates/a/src/lib.rs\" line-rate=\"0.7142857142857143\" branch-rate=\"0\" complexity=\"0\">\n\
+             \x20         <methods/>\n\
+             \x20         <lines>\n\
+             \x20           <line number=\"1\" hits=\"2\" branch=\"false\"/>\n\
+             \x20           <line number=\"2\" hits=\"2\" branch=\"false\"/>\n\
+             \x20           <line number=\"3\" hits=\"2\" branch=\"false\"/>\n\
+             \x20           <line number=\"4\" hits=\"1\" branch=\"false\"/>\n\
+             \x20           <line number=\"5\" hits=\"1\" branch=\"false\"/>\n\
+             \x20           <line number=\"6\" hits=\"0\" branch=\"false\"/>\n\
+             \x20           <line number=\"7\" hits=\"0\" branch=\"false\"/>\n\
+             \x20         </lines>\n\
+             \x20       </class>\n\
+             \x20     </classes>\n\
+             \x20   </package>\n\
+             \x20 </packages>\n\
+             </coverage>\n",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+}
+
+#[test]
+fn test_sonar() {
+    let mut coverage = fixture_coverage();
+    coverage.files[1].filename = "/other/src/lib.rs".into();
+    coverage.files[1].segments.truncate(1);
+    let xml = formats::sonar(&coverage, Path::new("/ws"));
+    assert_eq!(
+        xml,
+        "<coverage version=\"1\">\n\
+         \x20 <file path=\"src/lib.rs\">\n\
+         \x20   <lineToCover lineNumber=\"1\" covered=\"true\"/>\n\
+         \x20   <lineToCover lineNumber=\"2\" covered=\"true\"/>\n\
+         \x20   <lineToCover lineNumber=\"3\" covered=\"true\" branchesToCover=\"2\" coveredBranches=\"2\"/>\n\
+         \x20   <lineToCover lineNumber=\"4\" covered=\"true\"/>\n\
+         \x20   <lineToCover lineNumber=\"5\" covered=\"true\"/>\n\
+         \x20   <lineToCover lineNumber=\"6\" covered=\"false\"/>\n\
+         \x20   <lineToCover lineNumber=\"7\" covered=\"false\"/>\n\
+         \x20 </file>\n\
+         \x20 <file path=\"/other/src/lib.rs\">\n\
+         \x20   <lineToCover lineNumber=\"1\" covered=\"true\"/>\n\
+         \x20 </file>\n\
+         </coverage>\n"
+    );
+}
+
+#[test]
+fn test_codecov() {
+    let codecov = formats::codecov(&fixture_coverage(), Path::new("/ws"));
+    assert_eq!(
+        serde_json::to_value(&codecov).unwrap(),
+        serde_json::json!({
+            "coverage": {
+                "src/lib.rs": {"1": 2, "2": 2, "3": "2/2", "4": 1, "5": 1, "6": 0, "7": 0},
+                "crates/a/src/lib.rs": {"1": 2, "2": 2, "3": 2, "4": 1, "5": 1, "6": 0, "7": 0},
+            }
+        })
+    );
+}
+
+#[test]
+fn test_coveralls() {
+    let mut coverage = fixture_coverage();
+    coverage.files.truncate(1);
+    let coveralls = formats::coveralls(&coverage, Path::new("/ws"), |path| {
+        assert_eq!(path, "/ws/src/lib.rs");
+        Ok(b"1\n2\n3\n4\n5\n6\n7\n8\n9".to_vec())
+    })
+    .unwrap();
+    assert_eq!(
+        serde_json::to_value(&coveralls).unwrap(),
+        serde_json::json!({
+            "source_files": [{
+                "name": "src/lib.rs",
+                "source_digest": "283de9011ab36b60f6ddca02595ef259",
+                "coverage": [2, 2, 2, 1, 1, 0, 0, null, null],
+                "branches": [3, 0, 0, 1, 3, 0, 1, 1],
+            }]
+        })
+    );
+
+    // A trailing newline does not add a line.
+    let coveralls = formats::coveralls(&coverage, Path::new("/ws"), |_| {
+        Ok(b"1\n2\n3\n4\n5\n6\n7\n8\n".to_vec())
+    })
+    .unwrap();
+    assert_eq!(
+        coveralls.source_files[0].source_digest,
+        "cb7f5e5c765b1fc78547062ee6770596"
+    );
+    assert_eq!(
+        coveralls.source_files[0].coverage,
+        &[
+            Some(2),
+            Some(2),
+            Some(2),
+            Some(1),
+            Some(1),
+            Some(0),
+            Some(0),
+            None
+        ]
+    );
+
+    let r = formats::coveralls(&coverage, Path::new("/ws"), |_| {
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    })
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to read /ws/src/lib.rs");
+}
+
+#[test]
+fn test_llvm_cov_export_text_invalid() {
+    MOCK_RESULT.with(|o| {
+        o.borrow_mut()
+            .replace((br#"{"data":[{"files":[{"filename":"a","segments":[[1,1]],"summary":{}}],"functions":[],"totals":{}}]}"#, true))
+    });
+
+    let r = llvm_cov_export_text(
+        &PathBuf::from("llvm-cov"),
+        &[],
+        &PathBuf::from("default.profdata"),
+        &[PathBuf::from("exe")],
+        "",
+    )
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to parse llvm-cov export.");
+}
+
+#[test]
+fn test_to_obj_args() {
+    let r = to_obj_args(&[]);
+    assert_eq!(r, &[] as &[PathBuf]);
+
+    let v = vec![PathBuf::from("a")];
+    let r = to_obj_args(&v);
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0], v[0]);
+
+    let v = vec![PathBuf::from("a"), PathBuf::from("b")];
+    let r = to_obj_args(&v);
+    assert_eq!(r.len(), 3);
+    assert_eq!(r[0], v[0]);
+    assert_eq!(r[1].to_str().unwrap(), "-object");
+    assert_eq!(r[2], v[1]);
+}
+
+#[test]
+fn test_clean() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let target = tmpdir.join("cov");
+    for dir in &["profraw-1", "profraw-2", "html", "debug"] {
+        fs::create_dir_all(target.join(dir)).unwrap();
+    }
+    for file in &[
+        "profraw-1/1.profraw",
+        "default.profdata",
+        "manifest.json",
+        "cov.info",
+        "debug/exe",
+    ] {
+        fs::write(target.join(file), b"").unwrap();
+    }
+
+    clean(&target, true).unwrap();
+    let mut rest = fs::read_dir(&target)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    rest.sort();
+    assert_eq!(rest, &["debug"]);
+
+    clean(&target, false).unwrap();
+    assert!(!target.exists());
+    clean(&target, false).unwrap();
+
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "clean", "--keep-build"]);
+    assert!(matches!(
+        opts.action,
+        Some(Action::Clean(CleanOpts {
+            keep_build: true,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn test_manifest() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let manifest = Manifest {
+        executables: vec![
+            tmpdir.join("debug/deps/a-0123"),
+            PathBuf::from("/other/debug/deps/b-0123"),
+        ],
+        title: "a (no features)".into(),
+        ignore: "ignore".into(),
+    };
+    let path = Manifest::path(&tmpdir);
+    manifest.save(&path).unwrap();
+    let saved: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(
+        saved["executables"],
+        serde_json::json!(["debug/deps/a-0123", "/other/debug/deps/b-0123"])
+    );
+    assert_eq!(Manifest::load(&path).unwrap(), manifest);
+}
+
+fn tools() -> Tools {
+    Tools {
+        cargo: PathBuf::from("cargo"),
+        rustc_version: RustcVersion::parse("host: x86_64-unknown-linux-gnu\nrelease: 1.60.0\n")
+            .unwrap(),
+        llvm_profdata: PathBuf::from("llvm-profdata"),
+        llvm_cov: PathBuf::from("llvm-cov"),
+        demangler: vec!["demangler".into()],
+    }
+}
+
+#[test]
+fn test_write_report() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tools = tools();
+    let manifest = Manifest {
+        executables: vec![PathBuf::from("exe")],
+        title: "title".into(),
+        ignore: "ignore".into(),
+    };
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "report", "--html"]);
+    let report = match opts.action {
+        Some(Action::Report(opts)) => opts.report,
+        action => panic!("{:?}", action),
+    };
+
+    begin_capture();
+    write_report(
+        &report,
+        &tools,
+        &PathBuf::from("target/cov/default.profdata"),
+        &manifest,
+        &workspace_metadata(),
+        &PathBuf::from("target/cov"),
+    )
+    .unwrap();
+    assert_eq!(pop_captured().unwrap().message(), "generating report..");
+    assert_eq!(pop_captured().unwrap().message(),
+        "CALL llvm-cov show -Xdemangler=demangler exe -instr-profile=target/cov/default.profdata -format=html -output-dir=target/cov/html -project-title=title -ignore-filename-regex=ignore -show-instantiations=false");
+
+    for (args, expected) in [
+        (&["cargo", "llvmcov", "report"][..],
+            "CALL llvm-cov report exe -instr-profile=target/cov/default.profdata -ignore-filename-regex=ignore"),
+        (&["cargo", "llvmcov", "report", "--summary"][..],
+            "CALL llvm-cov report exe -instr-profile=target/cov/default.profdata -ignore-filename-regex=ignore"),
+        (&["cargo", "llvmcov", "report", "--text"][..],
+            "CALL llvm-cov show -Xdemangler=demangler exe -instr-profile=target/cov/default.profdata -format=text -project-title=title -ignore-filename-regex=ignore -show-instantiations=false"),
+    ] {
+        let SubCommand::Llvmcov(opts) = SubCommand::parse_from(args);
+        let report = match opts.action {
+            Some(Action::Report(opts)) => opts.report,
+            action => panic!("{:?}", action),
+        };
+
+        MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+        write_report(
+            &report,
+            &tools,
+            &PathBuf::from("target/cov/default.profdata"),
+            &manifest,
+            &workspace_metadata(),
+            &PathBuf::from("target/cov"),
+        )
+        .unwrap();
+        assert_eq!(pop_captured().unwrap().message(), "generating report..");
+        assert_eq!(pop_captured().unwrap().message(), expected);
+    }
+
+    assert!(
+        SubCommand::try_parse_from(["cargo", "llvmcov", "report", "--summary", "--text"]).is_err()
+    );
+}
+
+#[test]
+fn test_env_script() {
+    let flags = Flags {
+        rustflags: vec![
+            "--cfg".into(),
+            "foo='a b'".into(),
+            "-Zinstrument-coverage".into(),
+        ],
+        rustdocflags: vec![],
+        bootstrap: true,
+    };
+    let env = instrument_env(
+        &flags,
+        &PathBuf::from("/ws/target/cov/profraw-env/%p.profraw"),
+    );
+    assert_eq!(
+        env_script(&env),
+        "export RUSTC_BOOTSTRAP='1'\n\
+         export RUSTFLAGS='--cfg foo='\\''a b'\\'' -Zinstrument-coverage'\n\
+         export CARGO_ENCODED_RUSTFLAGS='--cfg\x1ffoo='\\''a b'\\''\x1f-Zinstrument-coverage'\n\
+         export LLVM_PROFILE_FILE='/ws/target/cov/profraw-env/%p.profraw'\n"
+    );
+
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from([
+        "cargo",
+        "llvmcov",
+        "show-env",
+        "--target",
+        "aarch64-unknown-linux-gnu",
+    ]);
+    assert!(matches!(
+        opts.action,
+        Some(Action::ShowEnv(ShowEnvOpts {
+            target: Some(_),
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn test_built_executables() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    for dir in &[
+        "debug/deps",
+        "debug/build/a-0123",
+        "aarch64-unknown-linux-gnu/debug/deps",
+    ] {
+        fs::create_dir_all(tmpdir.join(dir)).unwrap();
+    }
+    let exe = |name: &str| format!("{}{}", name, env::consts::EXE_SUFFIX);
+    for file in &[
+        exe("debug/deps/a-0123"),
+        "debug/deps/a-0123.d".to_owned(),
+        "debug/deps/liba-0123.rlib".to_owned(),
+        exe("debug/a"),
+        exe("debug/build/a-0123/build-script-build"),
+        exe("aarch64-unknown-linux-gnu/debug/deps/b-4567"),
+    ] {
+        fs::write(tmpdir.join(file), b"").unwrap();
+    }
+
+    assert_eq!(
+        built_executables(&tmpdir).unwrap(),
+        &[
+            tmpdir.join(exe("debug/deps/a-0123")),
+            tmpdir.join(exe("aarch64-unknown-linux-gnu/debug/deps/b-4567")),
+        ]
+    );
+}
+
+#[test]
+fn test_report_manifest_show_env() {
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let mut metadata = workspace_metadata();
+    metadata.target_directory = tmpdir.join("target");
+    let target = coverage_dir(&metadata.target_directory, |_| None);
+    assert_eq!(target, tmpdir.join("target/cov"));
+
+    let env = show_env(&Flags::default(), &target, None);
+    let var = |key: &str| {
+        env.iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.clone())
+    };
+    // cargo follows `CARGO_TARGET_DIR` in the next process.
+    metadata.target_directory = var("CARGO_TARGET_DIR").unwrap().into();
+    assert_eq!(coverage_dir(&metadata.target_directory, var), target);
+    // Some other project, not built by `show-env`.
+    assert_eq!(
+        coverage_dir(Path::new("/other/target"), var),
+        Path::new("/other/target/cov")
+    );
+
+    let exe = target.join(format!("debug/deps/root-0123{}", env::consts::EXE_SUFFIX));
+    fs::create_dir_all(target.join("debug/deps")).unwrap();
+    fs::write(&exe, b"").unwrap();
+    let profraw = var("LLVM_PROFILE_FILE").unwrap().replace("%p", "1");
+    fs::create_dir_all(Path::new(&profraw).parent().unwrap()).unwrap();
+    fs::write(&profraw, b"").unwrap();
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+    let manifest = report_manifest(&tools(), &metadata, &target).unwrap();
+    assert_eq!(manifest.executables, &[exe]);
+}
+
+#[test]
+fn test_report_manifest() {
+    let metadata = workspace_metadata();
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+
+    let r = report_manifest(&tools(), &metadata, &tmpdir).unwrap_err();
+    assert_eq!(
+        &r.to_string(),
+        "no coverage data found. Run with `--keep` or set up processes with `show-env` first."
+    );
+
+    let exe = tmpdir.join(format!("debug/deps/root-0123{}", env::consts::EXE_SUFFIX));
+    fs::create_dir_all(tmpdir.join("debug/deps")).unwrap();
+    fs::write(&exe, b"").unwrap();
+    fs::create_dir_all(tmpdir.join("profraw-env")).unwrap();
+    fs::write(tmpdir.join("profraw-env/1.profraw"), b"").unwrap();
+    // Left over by an earlier `--keep` run.
+    fs::create_dir_all(tmpdir.join("profraw-123")).unwrap();
+    fs::write(tmpdir.join("profraw-123/2.profraw"), b"").unwrap();
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+    begin_capture();
+    let manifest = report_manifest(&tools(), &metadata, &tmpdir).unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!(
+            "CALL llvm-profdata merge -sparse {} -o {}",
+            tmpdir.join("profraw-env/1.profraw").to_string_lossy(),
+            tmpdir.join("default.profdata").to_string_lossy()
+        )
+    );
+    let selected = selected_packages(&metadata, &BuildOpts::default()).unwrap();
+    assert_eq!(
+        manifest,
+        Manifest {
+            executables: vec![exe],
+            title: "root".into(),
+            ignore: ignore_regex(&[cargo_home(), rustup_home()], &metadata, &selected),
+        }
+    );
+
+    manifest.save(&Manifest::path(&tmpdir)).unwrap();
+    let r = report_manifest(&tools(), &metadata, &tmpdir).unwrap_err();
+    assert_eq!(
+        r.to_string(),
+        format!(
+            "found both kept coverage data ({}) and profiles of `show-env` processes ({}). Remove the one not to report.",
+            Manifest::path(&tmpdir).to_string_lossy(),
+            tmpdir.join("profraw-env").to_string_lossy()
+        )
+    );
+
+    fs::remove_file(tmpdir.join("profraw-env/1.profraw")).unwrap();
+    let r = report_manifest(&tools(), &metadata, &tmpdir).unwrap_err();
+    assert_eq!(
+        &r.to_string(),
+        "no kept coverage data found. Run with `--keep` first."
+    );
+    fs::write(tmpdir.join("default.profdata"), b"").unwrap();
+    assert_eq!(
+        report_manifest(&tools(), &metadata, &tmpdir).unwrap(),
+        manifest
+    );
+}
+
+#[test]
+fn test_doctor_table() {
+    let checks = vec![
+        Check::new("rustc", Ok("rustc 1.60.0 (LLVM 14.0)".into()), "unused"),
+        Check::new(
+            "llvm-cov",
+            Err(anyhow::anyhow!("No llvm-cov exists.")),
+            "Run `rustup component add llvm-tools-preview`.",
+        ),
+        Check::new("demangler", Err(anyhow::anyhow!("no exe.")), ""),
+    ];
+    assert_eq!(
+        doctor_table(&checks),
+        "PASS  rustc      rustc 1.60.0 (LLVM 14.0)\n\
+         FAIL  llvm-cov   No llvm-cov exists.\n\
+         \x20                fix: Run `rustup component add llvm-tools-preview`.\n\
+         FAIL  demangler  no exe.\n"
+    );
+
+    let SubCommand::Llvmcov(opts) =
+        SubCommand::parse_from(["cargo", "llvmcov", "doctor", "--toolchain", "nightly"]);
+    assert!(matches!(opts.action, Some(Action::Doctor(_))));
+}
+
+#[test]
+fn test_doctor_rustc_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    begin_capture();
+    let checks = doctor(&ToolchainOpts::default());
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!("CALL {} -vV", rustc().to_string_lossy())
+    );
+    // Nothing after the rustc version can be checked.
+    assert_eq!(
+        checks.iter().map(|check| check.name).collect::<Vec<_>>(),
+        &["rustc", "cargo metadata", "demangler"]
+    );
+    assert_eq!(
+        checks[0].result.as_ref().unwrap_err().to_string(),
+        "failed to run rustc -vV."
+    );
+}
+
+#[test]
+fn test_doctor_no_llvm_tools() {
+    MOCK_RESULT.with(|o| {
+        o.borrow_mut().replace((
+            b"host: x86_64-unknown-linux-gnu\nrelease: 1.60.0\nLLVM version: 14.0\n",
+            true,
+        ))
+    });
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let opts = ToolchainOpts {
+        toolchain: None,
+        llvm_path: Some(tmpdir.to_path_buf()),
+    };
+    let checks = doctor(&opts);
+    assert_eq!(
+        checks.iter().map(|check| check.name).collect::<Vec<_>>(),
+        &[
+            "rustc",
+            "cargo metadata",
+            "demangler",
+            "instrumentation",
+            "llvm-profdata",
+            "llvm-cov"
+        ]
+    );
+    assert_eq!(
+        checks[0].result.as_ref().unwrap(),
+        &format!("{} 1.60.0 (LLVM 14.0)", rustc().to_string_lossy())
+    );
+    assert_eq!(checks[3].result.as_ref().unwrap(), "-Cinstrument-coverage");
+    for (check, name) in checks[4..].iter().zip(&["llvm-profdata", "llvm-cov"]) {
+        assert_eq!(
+            check.result.as_ref().unwrap_err().to_string(),
+            format!("No {} exists.", llvm_tool(&tmpdir, name).to_string_lossy())
+        );
+        assert_eq!(
+            check.fix,
+            "Run `rustup component add llvm-tools-preview`, or use `--llvm-path` with LLVM 14 binaries."
+        );
+    }
+}
+
+#[test]
+fn test_demangle() {
+    let input = "_ZN4core3fmt9Formatter3pad17h0123456789abcdefE\n_RNvCs1234_7mycrate3foo\nmain\n";
+    let mut output = vec![];
+    demangle(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "core::fmt::Formatter::pad\nmycrate::foo\nmain\n"
+    );
+
+    let SubCommand::Llvmcov(opts) = SubCommand::parse_from(["cargo", "llvmcov", "demangle"]);
+    assert!(matches!(opts.action, Some(Action::Demangle)));
+}
+
+#[test]
+fn test_llvm_cov_show() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    begin_capture();
+    llvm_cov_show(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &profenv.profdata,
+        &[PathBuf::from("exe")],
+        None,
+        "ignore",
+        "title",
+    )
+    .unwrap();
+    assert_eq!(
+        pop_captured().unwrap().message(),
+        format!("CALL llvm-cov show -Xdemangler=demangler exe -instr-profile={} -format=text -project-title=title -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+}
+
+#[test]
+fn test_llvm_cov_show_html() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    begin_capture();
+    llvm_cov_show(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &profenv.profdata,
+        &[PathBuf::from("exe")],
+        Some(&PathBuf::from("output")),
+        "ignore",
+        "title",
+    )
+    .unwrap();
+    assert_eq!(pop_captured().unwrap().message(),
+        format!("CALL llvm-cov show -Xdemangler=demangler exe -instr-profile={} -format=html -output-dir=output -project-title=title -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+}
+
+#[test]
+fn test_llvm_cov_show_failed() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = llvm_cov_show(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &profenv.profdata,
+        &[PathBuf::from("exe")],
+        None,
+        "ignore",
+        "title",
+    )
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run llvm-cov.");
+}
+
+#[test]
+fn test_llvm_cov_report() {
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    begin_capture();
+    llvm_cov_report(
+        &PathBuf::from("llvm-cov"),
+        &PathBuf::from("target/cov/default.profdata"),
+        &[PathBuf::from("exe"), PathBuf::from("exe2")],
+        "ignore",
+        "title",
+    )
+    .unwrap();
+    assert_eq!(pop_captured().unwrap().message(),
+        "CALL llvm-cov report exe -object exe2 -instr-profile=target/cov/default.profdata -ignore-filename-regex=ignore");
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+    let r = llvm_cov_report(
+        &PathBuf::from("llvm-cov"),
+        &PathBuf::from("target/cov/default.profdata"),
+        &[PathBuf::from("exe")],
+        "ignore",
+        "title",
+    )
+    .unwrap_err();
+    assert_eq!(&r.to_string(), "failed to run llvm-cov.");
+}
+
+#[test]
+fn test_llvm_cov_export() {
+    let output = mktemp::Temp::new_file().unwrap();
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, true)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    begin_capture();
+    llvm_cov_export(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &profenv.profdata,
+        &[PathBuf::from("exe")],
+        &output,
+        "ignore",
+        "x (features: a, b)",
+    )
+    .unwrap();
+    assert_eq!(pop_captured().unwrap().message(),
+        format!("CALL llvm-cov export -Xdemangler=demangler exe -instr-profile={} -format=lcov -ignore-filename-regex=ignore -show-instantiations=false", tmpdir.join("default.profdata").to_string_lossy()));
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "TN:x__features__a__b_\n"
+    );
+}
+
+#[test]
+fn test_llvm_cov_export_failed() {
+    let output = mktemp::Temp::new_file().unwrap();
+
+    MOCK_RESULT.with(|o| o.borrow_mut().replace((br#""#, false)));
+
+    let tmpdir = mktemp::Temp::new_dir().unwrap();
+    let profenv = Profenv::new(&tmpdir).unwrap();
+
+    let r = llvm_cov_export(
+        &PathBuf::from("llvm-cov"),
+        &["demangler".to_owned()],
+        &profenv.profdata,
         &[PathBuf::from("exe")],
         &output,
         "ignore",
+        "x (features: a, b)",
     )
     .unwrap_err();
     assert_eq!(&r.to_string(), "failed to run llvm-cov.");